@@ -0,0 +1,1070 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    collections::{HashMap, HashSet},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use libakari::{
+    annotations::{dns_config, DnsConfig},
+    container_rpc::{
+        ContainerCommand, ContainerState, ContainerStats, ContainerStatus, CreateRequest,
+        ErrorCode, ExecRequest, ExitEvent, KillRequest, ProcessDetails, ResizePtyRequest, Stdio,
+    },
+    hooks::{self, Lifecycle},
+    validate::{valid_container_id, validate_process, validate_spec, ValidationErrors},
+};
+use oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{
+    hosts::{self, Network},
+    privileges, procs,
+    pty::Pty,
+    rootfs::{self, Rootfs},
+    state::StateStore,
+    stdio::StdioListeners,
+    transport::Transport,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Container already exists: {0}")]
+    ContainerAlreadyExists(String),
+    #[error("Container not found: {0}")]
+    ContainerNotFound(String),
+    #[error("Invalid container ID: {0:?}")]
+    InvalidContainerId(String),
+    #[error("Exec process already exists: {0}")]
+    ExecAlreadyExists(String),
+    #[error("Exec process not found: {0}")]
+    ExecNotFound(String),
+    #[error("Unexpected container status: {0:?}")]
+    UnexpectedContainerStatus(ContainerStatus),
+    #[error("Hooks of the container are running: {0}")]
+    HooksRunning(String),
+    #[error("Process is not specified")]
+    ProcessNotSpecified,
+    #[error("Root is not specified")]
+    RootNotSpecified,
+    #[error("Process args are empty")]
+    EmptyArgs,
+    #[error("Process has no terminal")]
+    NoTerminal,
+    #[error(transparent)]
+    InvalidSpec(#[from] ValidationErrors),
+    #[error("Path escapes the rootfs: {0:?}")]
+    PathOutsideRootfs(PathBuf),
+    #[error("Unsupported on the guest: {0}")]
+    Unsupported(String),
+    #[error(transparent)]
+    Hook(#[from] hooks::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::ContainerAlreadyExists(_) | Error::ExecAlreadyExists(_) => {
+                ErrorCode::AlreadyExists
+            }
+            Error::ContainerNotFound(_) | Error::ExecNotFound(_) => ErrorCode::NotFound,
+            Error::UnexpectedContainerStatus(_) | Error::HooksRunning(_) | Error::NoTerminal => {
+                ErrorCode::FailedPrecondition
+            }
+            Error::InvalidContainerId(_)
+            | Error::ProcessNotSpecified
+            | Error::RootNotSpecified
+            | Error::EmptyArgs
+            | Error::InvalidSpec(_)
+            | Error::PathOutsideRootfs(_)
+            | Error::Unsupported(_) => ErrorCode::InvalidArgument,
+            Error::Hook(_) | Error::Io(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+// Send a signal to a process, or to a process group if the pid is negative.
+fn send_signal(pid: libc::pid_t, signal: u32) -> std::io::Result<()> {
+    if unsafe { libc::kill(pid, signal as libc::c_int) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Exit code of a process whose exit status is unknown, as used by containerd. The exit status of
+// an adopted process goes to its parent, which is gone, so its exit is always reported with it.
+const UNKNOWN_EXIT_CODE: i32 = 255;
+
+// Check whether a process still exists. The pid of an exited process may have been reused by
+// another one, which is told apart by its start time.
+fn alive(pid: u32, start_time: Option<u64>) -> bool {
+    start_time.is_some() && procs::start_time(pid) == start_time
+}
+
+// The init process of a container or a process executed in it.
+// The stdio and the pty are held by the agent, and are not taken over by the next agent.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Process {
+    spec: oci_spec::runtime::Process,
+    // Root of the container, into which the process is chrooted.
+    root: PathBuf,
+    // Ports of the stdio, on which a restored process that has not started listens again.
+    ports: Stdio,
+    #[serde(skip)]
+    stdio: Option<StdioListeners>,
+    #[serde(skip)]
+    pty: Option<Pty>,
+    status: ContainerStatus,
+    pid: Option<u32>,
+    // Start time of the process, recorded to recognize it when it is adopted.
+    #[serde(default)]
+    start_time: Option<u64>,
+    exit_code: Option<i32>,
+    exited_at: Option<SystemTime>,
+    // Whether the process was started by a previous agent. It is not a child of this agent, so
+    // its exit cannot be reaped and is detected by polling.
+    #[serde(skip)]
+    adopted: bool,
+}
+
+impl Process {
+    fn new(
+        spec: oci_spec::runtime::Process,
+        root: &Path,
+        stdio: &Stdio,
+        transport: &Transport,
+    ) -> Result<Self, Error> {
+        // Check that the process can be built before registering it.
+        command(&spec, root)?;
+        let mut process = Self {
+            spec,
+            root: root.to_path_buf(),
+            ports: stdio.clone(),
+            stdio: None,
+            pty: None,
+            status: ContainerStatus::Created,
+            pid: None,
+            start_time: None,
+            exit_code: None,
+            exited_at: None,
+            adopted: false,
+        };
+        process.open_stdio(transport)?;
+        Ok(process)
+    }
+
+    fn open_stdio(&mut self, transport: &Transport) -> Result<(), Error> {
+        self.stdio = Some(StdioListeners::bind(transport, &self.ports)?);
+        if self.spec.terminal().unwrap_or(false) {
+            self.pty = Some(Pty::open()?);
+        }
+        Ok(())
+    }
+
+    // Take over a process saved by a previous agent.
+    // A created process gets its stdio again, and a running process is adopted if it still
+    // exists, and stopped otherwise.
+    fn restore(&mut self, transport: &Transport) -> Result<(), Error> {
+        match (self.status, self.pid) {
+            (ContainerStatus::Created, _) => self.open_stdio(transport)?,
+            (ContainerStatus::Running, Some(pid)) if alive(pid, self.start_time) => {
+                self.adopted = true
+            }
+            (ContainerStatus::Running, _) => {
+                self.stop(UNKNOWN_EXIT_CODE);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, exit_code: i32) -> SystemTime {
+        let exited_at = SystemTime::now();
+        self.status = ContainerStatus::Stopped;
+        self.exit_code = Some(exit_code);
+        self.exited_at = Some(exited_at);
+        exited_at
+    }
+
+    fn start(&mut self) -> Result<u32, Error> {
+        if self.status != ContainerStatus::Created {
+            return Err(Error::UnexpectedContainerStatus(self.status));
+        }
+        let mut cmd = command(&self.spec, &self.root)?;
+        let stdio = self.stdio.take().unwrap_or_default();
+        let child = match self.pty.as_mut() {
+            Some(pty) => {
+                pty.configure(&mut cmd)?;
+                let child = cmd.spawn()?;
+                stdio.forward_pty(pty.master().try_clone()?)?;
+                child
+            }
+            None => {
+                stdio.configure(&mut cmd);
+                // Lead a new process group so that signals can be sent to the whole group.
+                // A process with a pty leads a new session instead.
+                cmd.process_group(0);
+                let mut child = cmd.spawn()?;
+                stdio.forward(&mut child)?;
+                child
+            }
+        };
+        // The child is reaped by the reaper, not through the handle.
+        let pid = child.id();
+        self.pid = Some(pid);
+        // The process has not been reaped yet, so the pid still refers to it.
+        self.start_time = procs::start_time(pid);
+        self.status = ContainerStatus::Running;
+        Ok(pid)
+    }
+
+    // Send a signal to the process group led by the process, which includes the children that
+    // have not moved to another group.
+    fn kill(&mut self, signal: u32) -> Result<(), Error> {
+        if self.status != ContainerStatus::Running {
+            return Err(Error::UnexpectedContainerStatus(self.status));
+        }
+        if let Some(pid) = self.pid {
+            // The process has not been reaped yet, so the pid is still valid.
+            send_signal(-(pid as libc::pid_t), signal)?;
+        }
+        Ok(())
+    }
+
+    fn resize_pty(&self, width: u32, height: u32) -> Result<(), Error> {
+        let pty = self.pty.as_ref().ok_or(Error::NoTerminal)?;
+        pty.resize(width, height)?;
+        Ok(())
+    }
+
+    fn state(&self, id: &str, exec_id: Option<&str>) -> ContainerState {
+        ContainerState {
+            id: id.to_string(),
+            exec_id: exec_id.map(|exec_id| exec_id.to_string()),
+            status: self.status,
+            pid: self.pid,
+            exit_code: self.exit_code,
+            exited_at: self.exited_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Container {
+    spec: Box<Spec>,
+    bundle: PathBuf,
+    init: Process,
+    execs: HashMap<String, Process>,
+    // Unmounted when the container is deleted.
+    rootfs: Rootfs,
+    // Directory of the name resolution files mounted in the rootfs, removed with the container.
+    run_path: PathBuf,
+}
+
+// Name resolution files that the agent generates for the containers.
+const HOSTS_FILES: [&str; 3] = ["/etc/hosts", "/etc/resolv.conf", "/etc/hostname"];
+
+// Hooks of a container to be run without holding the registry.
+struct PendingHooks {
+    spec: Box<Spec>,
+    lifecycle: Lifecycle,
+    state: hooks::State,
+}
+
+impl PendingHooks {
+    // Run the hooks on a blocking thread, since each of them may take up to its timeout.
+    async fn run(self) -> Result<(), Error> {
+        tokio::task::spawn_blocking(move || hooks::run(&self.spec, self.lifecycle, &self.state))
+            .await
+            .map_err(std::io::Error::other)??;
+        Ok(())
+    }
+}
+
+impl Container {
+    fn hooks(&self, id: &str, lifecycle: Lifecycle, status: hooks::Status) -> PendingHooks {
+        PendingHooks {
+            spec: self.spec.clone(),
+            lifecycle,
+            state: hooks::State::new(&self.spec, id, status, self.init.pid, &self.bundle),
+        }
+    }
+
+    fn process_mut(&mut self, exec_id: Option<&str>) -> Result<&mut Process, Error> {
+        match exec_id {
+            Some(exec_id) => self
+                .execs
+                .get_mut(exec_id)
+                .ok_or_else(|| Error::ExecNotFound(exec_id.to_string())),
+            None => Ok(&mut self.init),
+        }
+    }
+
+    fn processes_mut(&mut self) -> impl Iterator<Item = (Option<&str>, &mut Process)> {
+        std::iter::once((None, &mut self.init)).chain(
+            self.execs
+                .iter_mut()
+                .map(|(exec_id, process)| (Some(exec_id.as_str()), process)),
+        )
+    }
+
+    // Send a signal to every process in the container: the process groups of the running
+    // processes, and their descendants that have moved to other groups.
+    fn kill_all(&mut self, signal: u32) -> Result<(), Error> {
+        let pids: Vec<u32> = self
+            .processes_mut()
+            .filter(|(_, process)| process.status == ContainerStatus::Running)
+            .filter_map(|(_, process)| process.pid)
+            .collect();
+        let processes = procs::list()?;
+        let groups = pids.iter().map(|pid| -(*pid as libc::pid_t));
+        let descendants = procs::descendants(&processes, &pids)
+            .into_iter()
+            .map(|process| process.pid as libc::pid_t);
+        let targets: Vec<libc::pid_t> = groups.chain(descendants).collect();
+        for target in targets {
+            match send_signal(target, signal) {
+                // The process may have exited in the meantime.
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    // Generate the name resolution files in the runtime directory of the container. They are
+    // rewritten in place, so that the mounts of them see the changes.
+    fn write_hosts(&self, network: &Network, hostnames: &[String]) -> Result<(), Error> {
+        let dns = match self.spec.annotations() {
+            Some(annotations) => dns_config(annotations).map_err(|e| ValidationErrors(vec![e]))?,
+            None => DnsConfig::default(),
+        };
+        let hostname = self.spec.hostname().as_deref();
+        let mut files = vec![
+            (
+                "/etc/hosts",
+                hosts::hosts_file(network, hostname, hostnames, &dns),
+            ),
+            ("/etc/resolv.conf", hosts::resolv_conf(network, &dns)),
+        ];
+        if let Some(hostname) = hostname {
+            files.push(("/etc/hostname", hosts::hostname_file(hostname)));
+        }
+        for (path, contents) in files {
+            std::fs::write(self.hosts_path(path), contents)?;
+        }
+        Ok(())
+    }
+
+    fn hosts_path(&self, path: &str) -> PathBuf {
+        self.run_path
+            .join(Path::new(path).file_name().unwrap_or_default())
+    }
+
+    // Mount the generated name resolution files over the rootfs, except those under spec.mounts,
+    // which are provided by the mounts. The rootfs itself is never written, since it is usually
+    // a directory shared from the host. A read-only root only gets the files that exist in it.
+    fn mount_hosts(&mut self) -> Result<(), Error> {
+        // An unprivileged agent cannot mount, and the files of the host are used instead.
+        if !rootfs::privileged() {
+            return Ok(());
+        }
+        let readonly = self
+            .spec
+            .root()
+            .as_ref()
+            .and_then(|root| root.readonly())
+            .unwrap_or(false);
+        for path in HOSTS_FILES {
+            let source = self.hosts_path(path);
+            if !source.exists() {
+                continue;
+            }
+            let path = Path::new(path);
+            let mounted = self
+                .spec
+                .mounts()
+                .iter()
+                .flatten()
+                .any(|mount| path.starts_with(mount.destination()));
+            let missing = !self
+                .rootfs
+                .path()
+                .join(path.strip_prefix("/").unwrap())
+                .exists();
+            if mounted || (readonly && missing) {
+                continue;
+            }
+            self.rootfs.mount_file(&source, path)?;
+        }
+        Ok(())
+    }
+
+    // Unmount the rootfs and remove the runtime directory.
+    fn remove(&mut self) {
+        self.rootfs.unmount();
+        if let Err(e) = std::fs::remove_dir_all(&self.run_path) {
+            log::error!("Failed to remove {:?}: {}", self.run_path, e);
+        }
+    }
+
+    // Exec processes can only be started in a running container.
+    fn check_running(&mut self) -> Result<(), Error> {
+        let init = self.process_mut(None)?;
+        if init.status != ContainerStatus::Running {
+            return Err(Error::UnexpectedContainerStatus(init.status));
+        }
+        Ok(())
+    }
+}
+
+fn command(process: &oci_spec::runtime::Process, root: &Path) -> Result<Command, Error> {
+    let cwd = process.cwd();
+    let args = process.args().as_ref().ok_or(Error::EmptyArgs)?;
+    let env = process.env();
+
+    if args.is_empty() {
+        return Err(Error::EmptyArgs);
+    }
+    let cmd = args[0].clone();
+    let args = &args[1..];
+
+    // The command is resolved in the rootfs, since it is spawned after chroot.
+    let mut cmd = Command::new(cmd);
+    cmd.args(args);
+    if let Some(env) = env {
+        // Create hashmap by parsing env strings like "key=value"
+        // Entries without "=" are rejected by the validation.
+        let envs: HashMap<&str, &str> = env.iter().filter_map(|e| e.split_once('=')).collect();
+        cmd.envs(envs);
+    }
+    rootfs::configure(&mut cmd, root, cwd)?;
+    privileges::configure(&mut cmd, process)?;
+
+    Ok(cmd)
+}
+
+// Keeps track of the containers created by the agent.
+// The state of each container is saved whenever it changes, so that it can be restored by the
+// next agent.
+pub struct ContainerRegistry {
+    containers: HashMap<String, Container>,
+    // Directory of the runtime directories of the containers.
+    run_dir: PathBuf,
+    // Containers whose hooks are running without the registry. A container being created is
+    // not in `containers` yet.
+    busy: HashSet<String>,
+    transport: Transport,
+    events: broadcast::Sender<ExitEvent>,
+    store: StateStore,
+}
+
+impl ContainerRegistry {
+    pub fn new(transport: Transport, store: StateStore, run_dir: &Path) -> Self {
+        const EVENT_CAPACITY: usize = 64;
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            containers: HashMap::new(),
+            run_dir: run_dir.to_path_buf(),
+            busy: HashSet::new(),
+            transport,
+            events,
+            store,
+        }
+    }
+
+    // Take over the containers saved by a previous agent.
+    pub fn restore(&mut self) -> Result<(), Error> {
+        for (id, mut container) in self.store.load_all::<Container>()? {
+            for (exec_id, process) in container.processes_mut() {
+                if let Err(e) = process.restore(&self.transport) {
+                    log::error!(
+                        "Failed to restore process {:?} of container {}: {}",
+                        exec_id,
+                        id,
+                        e
+                    );
+                }
+            }
+            log::info!("Restored container {}", id);
+            self.containers.insert(id.clone(), container);
+            self.save(&id);
+        }
+        Ok(())
+    }
+
+    // Save the state of a container. A failure is only logged, since the container itself is
+    // not affected.
+    fn save(&self, id: &str) {
+        let Some(container) = self.containers.get(id) else {
+            return;
+        };
+        if let Err(e) = self.store.save(id, container) {
+            log::error!("Failed to save the state of container {}: {}", id, e);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ExitEvent> {
+        self.events.subscribe()
+    }
+
+    // Record the exit of a reaped process and notify the subscribers.
+    pub fn exited(&mut self, pid: u32, exit_code: i32) {
+        if let Some(id) = self.stop_process(pid, exit_code) {
+            self.save(&id);
+        }
+    }
+
+    // Mark the running process with the pid stopped, and return the ID of its container.
+    fn stop_process(&mut self, pid: u32, exit_code: i32) -> Option<String> {
+        for (id, container) in self.containers.iter_mut() {
+            for (exec_id, process) in container.processes_mut() {
+                if process.pid != Some(pid) || process.status != ContainerStatus::Running {
+                    continue;
+                }
+                let exited_at = process.stop(exit_code);
+                log::info!(
+                    "Process {:?} of container {} exited with {}",
+                    exec_id,
+                    id,
+                    exit_code
+                );
+                // There may be no subscribers.
+                let _ = self.events.send(ExitEvent {
+                    container_id: id.clone(),
+                    exec_id: exec_id.map(|exec_id| exec_id.to_string()),
+                    pid,
+                    exit_code,
+                    exited_at,
+                });
+                return Some(id.clone());
+            }
+        }
+        None
+    }
+
+    // Pids of the running processes spawned by this agent, which are reaped by the reaper.
+    pub fn child_pids(&self) -> HashSet<u32> {
+        self.containers
+            .values()
+            .flat_map(|container| std::iter::once(&container.init).chain(container.execs.values()))
+            .filter(|process| !process.adopted && process.status == ContainerStatus::Running)
+            .filter_map(|process| process.pid)
+            .collect()
+    }
+
+    // Detect the exits of the adopted processes, which are not reaped by this agent.
+    pub fn poll_adopted(&mut self) {
+        let exited: Vec<u32> = self
+            .containers
+            .values_mut()
+            .flat_map(|container| container.processes_mut())
+            .filter_map(|(_, process)| {
+                let running = process.adopted && process.status == ContainerStatus::Running;
+                process
+                    .pid
+                    .filter(|pid| running && !alive(*pid, process.start_time))
+            })
+            .collect();
+        for pid in exited {
+            self.exited(pid, UNKNOWN_EXIT_CODE);
+        }
+    }
+
+    fn get_mut(&mut self, id: &str) -> Result<&mut Container, Error> {
+        self.containers
+            .get_mut(id)
+            .ok_or_else(|| Error::ContainerNotFound(id.to_string()))
+    }
+
+    // Hostnames of all the containers in the VM, which resolve to the VM.
+    fn hostnames(&self) -> Vec<String> {
+        let mut hostnames: Vec<String> = self
+            .containers
+            .values()
+            .filter_map(|container| container.spec.hostname().clone())
+            .collect();
+        hostnames.sort();
+        hostnames.dedup();
+        hostnames
+    }
+
+    // Regenerate the name resolution files of all the containers when a container is created
+    // or deleted, so that the containers resolve each other.
+    fn update_hosts(&self, network: &Network) {
+        let hostnames = self.hostnames();
+        for (id, container) in &self.containers {
+            if let Err(e) = container.write_hosts(network, &hostnames) {
+                log::error!("Failed to update the hosts of container {}: {}", id, e);
+            }
+        }
+    }
+
+    fn check_idle(&self, id: &str) -> Result<(), Error> {
+        if self.busy.contains(id) {
+            return Err(Error::HooksRunning(id.to_string()));
+        }
+        Ok(())
+    }
+
+    // Prepare a container, and return it with its createContainer hooks, which are to be run
+    // before `finish_create`.
+    fn begin_create(
+        &mut self,
+        req: CreateRequest,
+    ) -> Result<(String, Container, PendingHooks), Error> {
+        let id = req.container_id;
+        // The ID names the state file.
+        if !valid_container_id(&id) {
+            return Err(Error::InvalidContainerId(id));
+        }
+        if self.containers.contains_key(&id) || self.busy.contains(&id) {
+            return Err(Error::ContainerAlreadyExists(id));
+        }
+        validate_spec(&req.spec)?;
+        let process = req
+            .spec
+            .process()
+            .clone()
+            .ok_or(Error::ProcessNotSpecified)?;
+        let root = req.spec.root().as_ref().ok_or(Error::RootNotSpecified)?;
+        let mounts = req.spec.mounts().as_deref().unwrap_or_default();
+        let mut rootfs = Rootfs::prepare(root, mounts, &req.bundle)?;
+        let run_path = self.run_dir.join(&id);
+        let init = std::fs::create_dir_all(&run_path)
+            .map_err(Error::from)
+            .and_then(|()| Process::new(process, rootfs.path(), &req.stdio, &self.transport));
+        let init = match init {
+            Ok(init) => init,
+            Err(e) => {
+                rootfs.unmount();
+                let _ = std::fs::remove_dir_all(&run_path);
+                return Err(e);
+            }
+        };
+        let mut container = Container {
+            init,
+            spec: req.spec,
+            bundle: req.bundle,
+            execs: HashMap::new(),
+            rootfs,
+            run_path,
+        };
+        let network = Network::detect();
+        let mut hostnames = self.hostnames();
+        hostnames.extend(container.spec.hostname().clone());
+        let mounted = container
+            .write_hosts(&network, &hostnames)
+            .and_then(|()| container.mount_hosts());
+        if let Err(e) = mounted {
+            container.remove();
+            return Err(e);
+        }
+        let hooks = container.hooks(&id, Lifecycle::CreateContainer, hooks::Status::Creating);
+        self.busy.insert(id.clone());
+        Ok((id, container, hooks))
+    }
+
+    // Register a container prepared by `begin_create` if its hooks have succeeded.
+    fn finish_create(
+        &mut self,
+        id: String,
+        mut container: Container,
+        hooks: Result<(), Error>,
+    ) -> Result<ContainerState, Error> {
+        self.busy.remove(&id);
+        if let Err(e) = hooks {
+            container.remove();
+            return Err(e);
+        }
+        let state = container.init.state(&id, None);
+        self.containers.insert(id.clone(), container);
+        self.save(&id);
+        self.update_hosts(&Network::detect());
+        Ok(state)
+    }
+
+    fn exec(&mut self, req: ExecRequest) -> Result<ContainerState, Error> {
+        let transport = self.transport.clone();
+        let container = self.get_mut(&req.container_id)?;
+        container.check_running()?;
+        if container.execs.contains_key(&req.exec_id) {
+            return Err(Error::ExecAlreadyExists(req.exec_id));
+        }
+        validate_process(&req.process)?;
+        let process = Process::new(
+            *req.process,
+            container.rootfs.path(),
+            &req.stdio,
+            &transport,
+        )?;
+        let state = process.state(&req.container_id, Some(&req.exec_id));
+        container.execs.insert(req.exec_id, process);
+        self.save(&req.container_id);
+        Ok(state)
+    }
+
+    // Return the startContainer hooks if the init process is to be started, which are to be run
+    // before `finish_start`.
+    fn begin_start(
+        &mut self,
+        id: &str,
+        exec_id: Option<&str>,
+    ) -> Result<Option<PendingHooks>, Error> {
+        self.check_idle(id)?;
+        let container = self.get_mut(id)?;
+        if exec_id.is_some() || container.init.status != ContainerStatus::Created {
+            return Ok(None);
+        }
+        let hooks = container.hooks(id, Lifecycle::StartContainer, hooks::Status::Created);
+        self.busy.insert(id.to_string());
+        Ok(Some(hooks))
+    }
+
+    fn finish_start(
+        &mut self,
+        id: &str,
+        exec_id: Option<&str>,
+        hooks: Option<Result<(), Error>>,
+    ) -> Result<ContainerState, Error> {
+        if let Some(result) = hooks {
+            self.busy.remove(id);
+            result?;
+        }
+        let container = self.get_mut(id)?;
+        if exec_id.is_some() {
+            container.check_running()?;
+        }
+        let process = container.process_mut(exec_id)?;
+        let pid = process.start()?;
+        log::info!(
+            "Started process {:?} of container {} with pid {}",
+            exec_id,
+            id,
+            pid
+        );
+        let state = process.state(id, exec_id);
+        self.save(id);
+        Ok(state)
+    }
+
+    fn kill(&mut self, req: &KillRequest) -> Result<ContainerState, Error> {
+        let id = req.container_id.as_str();
+        let exec_id = req.exec_id.as_deref();
+        let container = self.get_mut(id)?;
+        // `all` only applies to the container as a whole.
+        if req.all && exec_id.is_none() {
+            container.kill_all(req.signal)?;
+            return Ok(container.init.state(id, None));
+        }
+        let process = container.process_mut(exec_id)?;
+        process.kill(req.signal)?;
+        Ok(process.state(id, exec_id))
+    }
+
+    fn resize_pty(&mut self, req: &ResizePtyRequest) -> Result<ContainerState, Error> {
+        let id = req.container_id.as_str();
+        let exec_id = req.exec_id.as_deref();
+        let process = self.get_mut(id)?.process_mut(exec_id)?;
+        process.resize_pty(req.width, req.height)?;
+        Ok(process.state(id, exec_id))
+    }
+
+    fn delete(&mut self, id: &str, exec_id: Option<&str>) -> Result<ContainerState, Error> {
+        self.check_idle(id)?;
+        let container = self.get_mut(id)?;
+        let process = container.process_mut(exec_id)?;
+        if process.status == ContainerStatus::Running {
+            return Err(Error::UnexpectedContainerStatus(process.status));
+        }
+        let state = process.state(id, exec_id);
+        match exec_id {
+            Some(exec_id) => {
+                container.execs.remove(exec_id);
+                self.save(id);
+                log::info!("Deleted process {} of container {}", exec_id, id);
+            }
+            None => {
+                container.remove();
+                self.containers.remove(id);
+                if let Err(e) = self.store.remove(id) {
+                    log::error!("Failed to remove the state of container {}: {}", id, e);
+                }
+                self.update_hosts(&Network::detect());
+                log::info!("Deleted container {}", id);
+            }
+        }
+        Ok(state)
+    }
+
+    // Sample the resource usage of the running processes of a container and their descendants.
+    pub fn stats(&mut self, id: &str) -> Result<ContainerStats, Error> {
+        let pids: Vec<u32> = self
+            .get_mut(id)?
+            .processes_mut()
+            .filter(|(_, process)| process.status == ContainerStatus::Running)
+            .filter_map(|(_, process)| process.pid)
+            .collect();
+        Ok(procs::stats(&pids)?)
+    }
+
+    // List the running processes of a container and their descendants, along with the exec
+    // process that each of them belongs to. Orphaned descendants are reparented out of the
+    // container, to the agent on Linux or to launchd on macOS, and are no longer listed.
+    pub fn pids(&mut self, id: &str) -> Result<Vec<(u32, ProcessDetails)>, Error> {
+        let roots: Vec<(Option<String>, u32)> = self
+            .get_mut(id)?
+            .processes_mut()
+            .filter(|(_, process)| process.status == ContainerStatus::Running)
+            .filter_map(|(exec_id, process)| Some((exec_id.map(str::to_string), process.pid?)))
+            .collect();
+        let processes = procs::list()?;
+        let mut pids = Vec::new();
+        for (exec_id, pid) in roots {
+            for process in procs::descendants(&processes, &[pid]) {
+                let details = ProcessDetails {
+                    ppid: process.ppid,
+                    exec_id: exec_id.clone(),
+                    command: process.command.clone(),
+                    cpu_time_ns: (process.user_time + process.system_time).as_nanos() as u64,
+                };
+                pids.push((process.pid, details));
+            }
+        }
+        pids.sort_by_key(|(pid, _)| *pid);
+        Ok(pids)
+    }
+
+    // Check that a container is running before connecting a published port to it.
+    pub fn check_running(&mut self, id: &str) -> Result<(), Error> {
+        self.get_mut(id)?.check_running()
+    }
+
+    pub fn state(&mut self, id: &str, exec_id: Option<&str>) -> Result<ContainerState, Error> {
+        let process = self.get_mut(id)?.process_mut(exec_id)?;
+        Ok(process.state(id, exec_id))
+    }
+}
+
+// Handle a command on the registry. The hooks in the container namespace run without holding
+// the registry, so that a slow hook does not stall the requests for the other containers.
+pub async fn handle_cmd(
+    registry: &Mutex<ContainerRegistry>,
+    cmd: ContainerCommand,
+) -> Result<ContainerState, Error> {
+    match cmd {
+        ContainerCommand::Create(req) => {
+            let (id, container, hooks) = registry.lock().await.begin_create(req)?;
+            let result = hooks.run().await;
+            registry.lock().await.finish_create(id, container, result)
+        }
+        ContainerCommand::Start(req) => {
+            let id = req.container_id.as_str();
+            let exec_id = req.exec_id.as_deref();
+            let hooks = registry.lock().await.begin_start(id, exec_id)?;
+            let result = match hooks {
+                Some(hooks) => Some(hooks.run().await),
+                None => None,
+            };
+            registry.lock().await.finish_start(id, exec_id, result)
+        }
+        ContainerCommand::Delete(req) => registry
+            .lock()
+            .await
+            .delete(&req.container_id, req.exec_id.as_deref()),
+        ContainerCommand::Exec(req) => registry.lock().await.exec(req),
+        ContainerCommand::Kill(req) => registry.lock().await.kill(&req),
+        ContainerCommand::ResizePty(req) => registry.lock().await.resize_pty(&req),
+        ContainerCommand::State(req) => registry
+            .lock()
+            .await
+            .state(&req.container_id, req.exec_id.as_deref()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::transport::TransportKind;
+
+    use ContainerStatus::{Created, Running, Stopped};
+
+    // A directory under the temporary directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("akari-agent-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn process(status: ContainerStatus, pid: Option<u32>, start_time: Option<u64>) -> Process {
+        Process {
+            spec: oci_spec::runtime::Process::default(),
+            root: PathBuf::from("/"),
+            ports: Stdio::default(),
+            stdio: None,
+            pty: None,
+            status,
+            pid,
+            start_time,
+            exit_code: None,
+            exited_at: None,
+            adopted: false,
+        }
+    }
+
+    // A container whose processes run in the root of the agent, with nothing mounted.
+    fn container(run_path: PathBuf, init: Process) -> Container {
+        Container {
+            spec: Box::default(),
+            bundle: PathBuf::from("/"),
+            init,
+            execs: HashMap::new(),
+            rootfs: serde_json::from_str(r#"{"path": "/", "mounts": []}"#).unwrap(),
+            run_path,
+        }
+    }
+
+    fn registry(dir: &TempDir) -> ContainerRegistry {
+        let transport = Transport::new(TransportKind::Unix, dir.0.join("sockets"));
+        let store = StateStore::new(&dir.0.join("state")).unwrap();
+        ContainerRegistry::new(transport, store, &dir.0.join("run"))
+    }
+
+    fn status(registry: &mut ContainerRegistry, id: &str) -> (ContainerStatus, Option<i32>) {
+        let state = registry.state(id, None).unwrap();
+        (state.status, state.exit_code)
+    }
+
+    #[test]
+    fn stop_and_delete_processes() {
+        let dir = TempDir::new("lifecycle");
+        let mut registry = registry(&dir);
+        let mut events = registry.subscribe();
+        let run_path = dir.0.join("run/web");
+        fs::create_dir_all(&run_path).unwrap();
+        let mut web = container(run_path.clone(), process(Running, Some(100), None));
+        web.execs
+            .insert("sh".to_string(), process(Running, Some(101), None));
+        registry.containers.insert("web".to_string(), web);
+        assert_eq!(registry.child_pids(), HashSet::from([100, 101]));
+
+        // A running process can be neither started again nor deleted.
+        assert!(matches!(
+            registry.finish_start("web", None, None),
+            Err(Error::UnexpectedContainerStatus(Running))
+        ));
+        assert!(matches!(
+            registry.delete("web", Some("sh")),
+            Err(Error::UnexpectedContainerStatus(Running))
+        ));
+
+        registry.exited(101, 3);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.container_id, "web");
+        assert_eq!(event.exec_id.as_deref(), Some("sh"));
+        assert_eq!((event.pid, event.exit_code), (101, 3));
+        let state = registry.state("web", Some("sh")).unwrap();
+        assert_eq!((state.status, state.exit_code), (Stopped, Some(3)));
+        assert_eq!(registry.child_pids(), HashSet::from([100]));
+        // Only the exits of the running processes are recorded.
+        registry.exited(101, 4);
+        registry.exited(999, 0);
+        assert!(events.try_recv().is_err());
+
+        registry.delete("web", Some("sh")).unwrap();
+        assert!(matches!(
+            registry.state("web", Some("sh")),
+            Err(Error::ExecNotFound(_))
+        ));
+
+        registry.exited(100, 0);
+        assert_eq!(status(&mut registry, "web"), (Stopped, Some(0)));
+        assert!(matches!(
+            registry.finish_start("web", None, None),
+            Err(Error::UnexpectedContainerStatus(Stopped))
+        ));
+        registry.busy.insert("web".to_string());
+        assert!(matches!(
+            registry.delete("web", None),
+            Err(Error::HooksRunning(_))
+        ));
+        registry.busy.remove("web");
+        registry.delete("web", None).unwrap();
+        assert!(matches!(
+            registry.state("web", None),
+            Err(Error::ContainerNotFound(_))
+        ));
+        assert!(!run_path.exists());
+        assert!(registry.store.load_all::<Container>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restore_saved_processes() {
+        let dir = TempDir::new("restore");
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = child.id();
+        let started = procs::start_time(pid);
+        assert!(started.is_some());
+        let store = StateStore::new(&dir.0.join("state")).unwrap();
+        let saved = [
+            ("created", process(Created, None, None)),
+            ("running", process(Running, Some(pid), started)),
+            // The pid has been reused by another process.
+            (
+                "reused",
+                process(Running, Some(pid), started.map(|t| t + 1)),
+            ),
+            // The start time was not recorded by the previous agent.
+            ("unknown", process(Running, Some(pid), None)),
+        ];
+        for (id, init) in saved {
+            store
+                .save(id, &container(dir.0.join("run").join(id), init))
+                .unwrap();
+        }
+
+        let mut registry = registry(&dir);
+        registry.restore().unwrap();
+        assert_eq!(status(&mut registry, "created"), (Created, None));
+        assert_eq!(status(&mut registry, "running"), (Running, None));
+        let stopped = (Stopped, Some(UNKNOWN_EXIT_CODE));
+        assert_eq!(status(&mut registry, "reused"), stopped);
+        assert_eq!(status(&mut registry, "unknown"), stopped);
+        // The adopted process is not reaped by the agent.
+        assert!(registry.child_pids().is_empty());
+
+        let mut events = registry.subscribe();
+        registry.poll_adopted();
+        assert!(events.try_recv().is_err());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        registry.poll_adopted();
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.container_id, "running");
+        assert_eq!((event.pid, event.exit_code), (pid, UNKNOWN_EXIT_CODE));
+        assert_eq!(status(&mut registry, "running"), stopped);
+    }
+}
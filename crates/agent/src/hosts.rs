@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Name resolution files generated for the containers: /etc/hostname, /etc/hosts and
+// /etc/resolv.conf. They are mounted over the rootfs, which is left as it is.
+// The containers share the network of the VM, so the hostnames of all the containers resolve to
+// the address of the VM, and the host resolves as `HOST_NAME` to the gateway of the VM.
+// macOS has no UTS namespace, so the hostname is not set in the kernel, which is shared by the VM.
+
+use std::{
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket},
+};
+
+use libakari::annotations::DnsConfig;
+
+// Name of the host in /etc/hosts.
+pub const HOST_NAME: &str = "host.akari.internal";
+
+const HEADER: &str = "# Generated by akari\n";
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+// Network configuration of the VM, detected when the files are generated.
+pub struct Network {
+    address: Option<IpAddr>,
+    gateway: Option<IpAddr>,
+    dns: DnsConfig,
+}
+
+impl Network {
+    pub fn detect() -> Self {
+        let gateway = default_gateway();
+        // Connecting a UDP socket sends nothing, but selects the address to reach the gateway.
+        let address = gateway.and_then(|gateway| {
+            let unspecified = match gateway {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let socket = UdpSocket::bind((unspecified, 0)).ok()?;
+            socket.connect((gateway, 53)).ok()?;
+            Some(socket.local_addr().ok()?.ip())
+        });
+        let dns = std::fs::read_to_string(RESOLV_CONF_PATH)
+            .map(|resolv_conf| parse_resolv_conf(&resolv_conf))
+            .unwrap_or_default();
+        Self {
+            address,
+            gateway,
+            dns,
+        }
+    }
+}
+
+// Read the default gateway from the IPv4 routing table, in which the addresses are hexadecimal
+// in the byte order of the host.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|route| {
+        let fields: Vec<&str> = route.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<IpAddr> {
+    let output = std::process::Command::new("/sbin/route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:"))
+        .and_then(|gateway| gateway.trim().parse().ok())
+}
+
+// Read the name servers, search domains and options of the VM. Name servers that are not plain
+// addresses, such as scoped IPv6 addresses, are skipped.
+fn parse_resolv_conf(resolv_conf: &str) -> DnsConfig {
+    let mut dns = DnsConfig::default();
+    for line in resolv_conf.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => dns.nameservers.extend(
+                fields
+                    .next()
+                    .and_then(|nameserver| nameserver.parse::<IpAddr>().ok()),
+            ),
+            Some("search") | Some("domain") => {
+                dns.search = fields.map(str::to_string).collect();
+            }
+            Some("options") => dns.options.extend(fields.map(str::to_string)),
+            _ => {}
+        }
+    }
+    dns
+}
+
+pub fn hostname_file(hostname: &str) -> String {
+    format!("{}\n", hostname)
+}
+
+// Generate /etc/hosts of a container with its hostname, the hostnames of the other containers in
+// the VM and the extra hosts from the annotations.
+pub fn hosts_file(
+    network: &Network,
+    hostname: Option<&str>,
+    others: &[String],
+    dns: &DnsConfig,
+) -> String {
+    let mut hosts = String::from(HEADER);
+    hosts.push_str("127.0.0.1\tlocalhost\n::1\tlocalhost\n");
+    let address = network.address.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let mut names: Vec<&str> = hostname.into_iter().collect();
+    names.extend(
+        others
+            .iter()
+            .map(String::as_str)
+            .filter(|other| Some(*other) != hostname),
+    );
+    if !names.is_empty() {
+        let _ = writeln!(hosts, "{}\t{}", address, names.join(" "));
+    }
+    if let Some(gateway) = network.gateway {
+        let _ = writeln!(hosts, "{}\t{}", gateway, HOST_NAME);
+    }
+    for (name, address) in &dns.hosts {
+        let _ = writeln!(hosts, "{}\t{}", address, name);
+    }
+    hosts
+}
+
+fn or<'a, T>(container: &'a [T], vm: &'a [T]) -> &'a [T] {
+    if container.is_empty() {
+        vm
+    } else {
+        container
+    }
+}
+
+// Generate /etc/resolv.conf of a container. The settings missing in the annotations are taken
+// from the VM.
+pub fn resolv_conf(network: &Network, dns: &DnsConfig) -> String {
+    let mut resolv_conf = String::from(HEADER);
+    for nameserver in or(&dns.nameservers, &network.dns.nameservers) {
+        let _ = writeln!(resolv_conf, "nameserver {}", nameserver);
+    }
+    let search = or(&dns.search, &network.dns.search);
+    if !search.is_empty() {
+        let _ = writeln!(resolv_conf, "search {}", search.join(" "));
+    }
+    let options = or(&dns.options, &network.dns.options);
+    if !options.is_empty() {
+        let _ = writeln!(resolv_conf, "options {}", options.join(" "));
+    }
+    resolv_conf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network() -> Network {
+        Network {
+            address: Some("192.168.64.2".parse().unwrap()),
+            gateway: Some("192.168.64.1".parse().unwrap()),
+            dns: DnsConfig {
+                nameservers: vec!["192.168.64.1".parse().unwrap()],
+                search: vec!["local".to_string()],
+                options: vec!["edns0".to_string()],
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn parse_vm_resolv_conf() {
+        let dns = parse_resolv_conf(
+            "# comment\n\
+             nameserver 1.1.1.1\n\
+             nameserver fe80::1%en0\n\
+             nameserver 2001:db8::1\n\
+             domain example.com\n\
+             search a.example b.example\n\
+             options ndots:2\n\
+             options edns0 rotate\n\
+             nameserver\n",
+        );
+        assert_eq!(
+            dns,
+            DnsConfig {
+                hosts: vec![],
+                nameservers: vec!["1.1.1.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+                search: vec!["a.example".to_string(), "b.example".to_string()],
+                options: vec![
+                    "ndots:2".to_string(),
+                    "edns0".to_string(),
+                    "rotate".to_string()
+                ],
+            }
+        );
+        assert_eq!(parse_resolv_conf(""), DnsConfig::default());
+    }
+
+    #[test]
+    fn generate_hosts_file() {
+        let dns = DnsConfig {
+            hosts: vec![("db".to_string(), "10.0.0.5".parse().unwrap())],
+            ..Default::default()
+        };
+        let others = ["web".to_string(), "app".to_string()];
+        assert_eq!(
+            hosts_file(&network(), Some("app"), &others, &dns),
+            "# Generated by akari\n\
+             127.0.0.1\tlocalhost\n\
+             ::1\tlocalhost\n\
+             192.168.64.2\tapp web\n\
+             192.168.64.1\thost.akari.internal\n\
+             10.0.0.5\tdb\n"
+        );
+    }
+
+    #[test]
+    fn generate_hosts_file_without_network() {
+        let network = Network {
+            address: None,
+            gateway: None,
+            dns: DnsConfig::default(),
+        };
+        assert_eq!(
+            hosts_file(&network, None, &[], &DnsConfig::default()),
+            "# Generated by akari\n127.0.0.1\tlocalhost\n::1\tlocalhost\n"
+        );
+        assert_eq!(
+            hosts_file(&network, None, &["web".to_string()], &DnsConfig::default()),
+            "# Generated by akari\n127.0.0.1\tlocalhost\n::1\tlocalhost\n127.0.0.1\tweb\n"
+        );
+    }
+
+    #[test]
+    fn generate_resolv_conf_from_vm() {
+        assert_eq!(
+            resolv_conf(&network(), &DnsConfig::default()),
+            "# Generated by akari\nnameserver 192.168.64.1\nsearch local\noptions edns0\n"
+        );
+    }
+
+    #[test]
+    fn generate_resolv_conf_from_annotations() {
+        let dns = DnsConfig {
+            nameservers: vec!["8.8.8.8".parse().unwrap(), "8.8.4.4".parse().unwrap()],
+            options: vec!["ndots:2".to_string()],
+            ..Default::default()
+        };
+        // The search domains missing in the annotations are taken from the VM.
+        assert_eq!(
+            resolv_conf(&network(), &dns),
+            "# Generated by akari\n\
+             nameserver 8.8.8.8\n\
+             nameserver 8.8.4.4\n\
+             search local\n\
+             options ndots:2\n"
+        );
+    }
+}
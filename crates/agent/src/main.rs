@@ -2,77 +2,269 @@
 // Copyright (C) 2024 Akira Moroo
 
 //! Akari Guest Agent
-//! This is a daemon that listens for requests from the host.
+//! This is a daemon that serves the containerd shim v2 task API to the host over ttrpc.
+//! It also pushes `ExitEvent`s to the host on an events port as it reaps the container
+//! processes.
+//! The connections to the published ports of the host are tunneled to a ports port, and
+//! connected to the localhost of the guest.
+//! The agent listens on vsock inside the VM, or on Unix domain or loopback TCP sockets
+//! when it runs directly on a host.
 
-use std::{
-    collections::HashMap,
-    io::Read,
-    process::{Command, Stdio},
-};
+mod container;
+mod hosts;
+mod privileges;
+mod procs;
+mod pty;
+mod reaper;
+mod rootfs;
+mod service;
+mod state;
+mod stdio;
+mod transport;
+
+use std::{future::Future, os::fd::IntoRawFd, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use libakari::container_rpc::ContainerCommand;
-use oci_spec::runtime::Spec;
-use vsock::{VsockAddr, VsockListener, VMADDR_CID_ANY};
-
-fn create(config: Spec) -> Result<()> {
-    let process = config.process().as_ref().unwrap();
-    let cwd = process.cwd();
-    let args = process.args().as_ref().unwrap();
-    let env = process.env();
-
-    assert!(!args.is_empty());
-    let cmd = args[0].clone();
-    let args = &args[1..];
-
-    let mut cmd = Command::new(cmd);
-    cmd.current_dir(cwd);
-    cmd.args(args);
-    if let Some(env) = env {
-        // Create hashmap by parsing env strings like "key=value"
-        let envs: HashMap<String, String> = env
-            .iter()
-            .map(|e| {
-                let mut split = e.splitn(2, '=');
-                (
-                    split.next().unwrap().to_string(),
-                    split.next().unwrap().to_string(),
-                )
-            })
-            .collect();
-        cmd.envs(envs);
+use clap::Parser;
+use containerd_shim::{protos::shim_async::create_task, Task as ShimTask};
+use libakari::container_rpc::{
+    self, PortConnectRequest, PortConnectResponse, AGENT_EVENTS_VSOCK_PORT, AGENT_PORTS_VSOCK_PORT,
+    AGENT_VSOCK_PORT,
+};
+use tokio::{
+    net::{TcpStream, UnixStream},
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast::error::RecvError, watch, Mutex, Semaphore},
+};
+use ttrpc::asynchronous::Server;
+
+use container::ContainerRegistry;
+use service::AgentService;
+use state::StateStore;
+use transport::{Listener, Stream, Transport, TransportKind};
+
+#[derive(clap::Parser)]
+struct Opts {
+    /// Transport to listen on
+    #[clap(short, long, value_enum, default_value = "vsock")]
+    transport: TransportKind,
+    /// Directory to place the agent sockets in
+    #[clap(short, long, default_value = "/tmp/akari-agent")]
+    socket_dir: PathBuf,
+    /// Directory to persist the container states in
+    #[clap(long, default_value = "/tmp/akari-agent/state")]
+    state_dir: PathBuf,
+    /// Directory to place the files generated for the containers in
+    #[clap(long, default_value = "/tmp/akari-agent/run")]
+    run_dir: PathBuf,
+    /// Maximum number of connections handled at a time
+    #[clap(long, default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+    max_connections: u32,
+    /// Maximum number of connections tunneled to the published ports at a time
+    #[clap(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    max_port_connections: u32,
+}
+
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Accept connections and handle each of them in its own task, so that an error on one
+// connection only closes that connection. At most as many connections as the permits of `limit`
+// are handled at a time, and the rest wait in the listen backlog.
+async fn serve<F, Fut>(
+    name: &'static str,
+    listener: Box<dyn Listener>,
+    limit: Arc<Semaphore>,
+    handle: F,
+) -> Result<()>
+where
+    F: Fn(Box<dyn Stream>) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        let permit = limit.clone().acquire_owned().await?;
+        let stream = match listener.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Failed to accept a {} connection: {}", name, e);
+                // The error may persist, e.g. when the agent runs out of file descriptors.
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        log::info!("Accepted a new {} connection", name);
+
+        let handler = handle(stream);
+        tokio::spawn(async move {
+            match handler.await {
+                Ok(()) => log::info!("Closed a {} connection", name),
+                Err(e) => log::error!("Closed a {} connection: {}", name, e),
+            }
+            drop(permit);
+        });
+    }
+}
+
+// Push the exit events to a connection until it is closed or the agent shuts down.
+async fn handle_events(
+    mut stream: Box<dyn Stream>,
+    registry: Arc<Mutex<ContainerRegistry>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut events = registry.lock().await.subscribe();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                log::warn!("Dropped {} exit events", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        container_rpc::write_message_async(&mut stream, &event).await?;
+    }
+}
+
+// Connect a tunnel from a published port of the host to a port on the localhost of the guest,
+// and forward it until either side closes it or the agent shuts down.
+async fn handle_port(
+    mut stream: Box<dyn Stream>,
+    registry: Arc<Mutex<ContainerRegistry>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let Some(req) = container_rpc::read_message_async::<_, PortConnectRequest>(&mut stream).await?
+    else {
+        return Ok(());
+    };
+    let running = registry.lock().await.check_running(&req.container_id);
+    let connected = match running {
+        Ok(()) => TcpStream::connect(("localhost", req.port))
+            .await
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e.into()),
+    };
+    let mut local = match connected {
+        Ok(local) => {
+            container_rpc::write_message_async(&mut stream, &PortConnectResponse::Connected)
+                .await?;
+            local
+        }
+        Err(e) => {
+            let res = PortConnectResponse::Error(e.to_string());
+            container_rpc::write_message_async(&mut stream, &res).await?;
+            return Err(e);
+        }
+    };
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut stream, &mut local) => {
+            result?;
+        }
+        _ = shutdown.changed() => {}
     }
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
-    cmd.stdin(Stdio::piped());
+    Ok(())
+}
 
+// Forward a connection to the ttrpc server listening on a local Unix domain socket.
+// The connection is closed by the ttrpc server when it shuts down.
+async fn forward_ttrpc(mut stream: Box<dyn Stream>, ttrpc_path: PathBuf) -> Result<()> {
+    let mut ttrpc_stream = UnixStream::connect(&ttrpc_path).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut ttrpc_stream).await?;
     Ok(())
 }
 
-fn handle_cmd(cmd: ContainerCommand) -> Result<()> {
-    match cmd {
-        ContainerCommand::Create(config) => create(*config),
-        ContainerCommand::Delete => todo!(),
-        ContainerCommand::Kill => todo!(),
-        ContainerCommand::Start => todo!(),
-        ContainerCommand::State => todo!(),
+// Wait for SIGTERM or SIGINT.
+async fn shutdown_signal() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
     }
+    Ok(())
 }
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     env_logger::init();
 
-    let addr = VsockAddr::new(VMADDR_CID_ANY, 9999);
-    let listener = VsockListener::bind(&addr)?;
+    let opts = Opts::parse();
+
+    std::fs::create_dir_all(&opts.socket_dir)?;
+    let transport = Transport::new(opts.transport, opts.socket_dir.clone());
+
+    let store = StateStore::new(&opts.state_dir)?;
+    let mut registry = ContainerRegistry::new(transport.clone(), store, &opts.run_dir);
+
+    reaper::set_subreaper()?;
+    // Register the handler before any process is spawned.
+    let sigchld = signal(SignalKind::child())?;
+
+    registry.restore()?;
+    let registry = Arc::new(Mutex::new(registry));
 
-    for stream in listener.incoming() {
-        let mut stream = stream?;
-        log::info!("Accepted a new connection from {}", stream.peer_addr()?);
+    let ttrpc_path = opts.socket_dir.join("ttrpc.sock");
+    if ttrpc_path.exists() {
+        std::fs::remove_file(&ttrpc_path)?;
+    }
+    let ttrpc_listener = std::os::unix::net::UnixListener::bind(&ttrpc_path)?;
+    ttrpc_listener.set_nonblocking(true)?;
+
+    let service = Box::new(AgentService::new(registry.clone())) as Box<dyn ShimTask + Sync + Send>;
+    let mut server = Server::new()
+        .add_listener(ttrpc_listener.into_raw_fd())?
+        .set_domain_unix()
+        .register_service(create_task(service.into()));
+
+    server.start().await?;
+
+    let events_listener = transport.bind(AGENT_EVENTS_VSOCK_PORT)?;
+    let ports_listener = transport.bind(AGENT_PORTS_VSOCK_PORT)?;
+    let listener = transport.bind(AGENT_VSOCK_PORT)?;
+
+    let limit = Arc::new(Semaphore::new(opts.max_connections as usize));
+    // The tunnels live as long as the connections to the published ports, so they have their own
+    // limit to keep idle clients from blocking the task API.
+    let port_limit = Arc::new(Semaphore::new(opts.max_port_connections as usize));
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let servers = async {
+        tokio::try_join!(
+            serve("events", events_listener, limit.clone(), |stream| {
+                handle_events(stream, registry.clone(), shutdown.clone())
+            }),
+            serve("ports", ports_listener, port_limit.clone(), |stream| {
+                handle_port(stream, registry.clone(), shutdown.clone())
+            }),
+            serve("ttrpc", listener, limit.clone(), |stream| {
+                forward_ttrpc(stream, ttrpc_path.clone())
+            }),
+        )
+    };
+
+    tokio::select! {
+        result = reaper::reap(sigchld, registry.clone()) => result?,
+        result = servers => {
+            result?;
+        }
+        result = shutdown_signal() => result?,
+    }
 
-        let mut buf = [0; 1024];
-        let n = stream.read(&mut buf)?;
-        let cmd = serde_json::from_slice(&buf[..n])?;
-        handle_cmd(cmd)?;
+    // Stop accepting connections, and wait for the connections being handled to be closed.
+    log::info!("Shutting down");
+    let _ = shutdown_tx.send(true);
+    server.shutdown().await?;
+    let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        tokio::try_join!(
+            limit.acquire_many(opts.max_connections),
+            port_limit.acquire_many(opts.max_port_connections),
+        )
+    })
+    .await;
+    if drained.is_err() {
+        log::warn!("Timed out waiting for the connections to be closed");
     }
 
     Ok(())
@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{io, os::unix::process::CommandExt, process::Command};
+
+use oci_spec::runtime::{PosixRlimitType, Process};
+
+use crate::container::Error;
+
+fn rlimit_resource(typ: PosixRlimitType) -> i32 {
+    let resource = match typ {
+        PosixRlimitType::RlimitCpu => libc::RLIMIT_CPU,
+        PosixRlimitType::RlimitFsize => libc::RLIMIT_FSIZE,
+        PosixRlimitType::RlimitData => libc::RLIMIT_DATA,
+        PosixRlimitType::RlimitStack => libc::RLIMIT_STACK,
+        PosixRlimitType::RlimitCore => libc::RLIMIT_CORE,
+        PosixRlimitType::RlimitNofile => libc::RLIMIT_NOFILE,
+        PosixRlimitType::RlimitAs => libc::RLIMIT_AS,
+        // oci-spec only has the following types on Linux.
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitRss => libc::RLIMIT_RSS,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitNproc => libc::RLIMIT_NPROC,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitMemlock => libc::RLIMIT_MEMLOCK,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitLocks => libc::RLIMIT_LOCKS,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitSigpending => libc::RLIMIT_SIGPENDING,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitMsgqueue => libc::RLIMIT_MSGQUEUE,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitNice => libc::RLIMIT_NICE,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitRtprio => libc::RLIMIT_RTPRIO,
+        #[cfg(target_os = "linux")]
+        PosixRlimitType::RlimitRttime => libc::RLIMIT_RTTIME,
+    };
+    resource as i32
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Apply the user, umask, rlimits and noNewPrivileges of the process when it is spawned.
+// The rlimits are set before dropping the privileges so that the hard limits can be raised.
+pub fn configure(cmd: &mut Command, process: &Process) -> Result<(), Error> {
+    let user = process.user();
+    let uid = user.uid() as libc::uid_t;
+    let gid = user.gid() as libc::gid_t;
+    let additional_gids: Vec<libc::gid_t> = user
+        .additional_gids()
+        .iter()
+        .flatten()
+        .map(|gid| *gid as libc::gid_t)
+        .collect();
+    let umask = user.umask().map(|umask| umask as libc::mode_t);
+    let rlimits: Vec<(i32, libc::rlimit)> = process
+        .rlimits()
+        .iter()
+        .flatten()
+        .map(|rlimit| {
+            (
+                rlimit_resource(rlimit.typ()),
+                libc::rlimit {
+                    rlim_cur: rlimit.soft() as libc::rlim_t,
+                    rlim_max: rlimit.hard() as libc::rlim_t,
+                },
+            )
+        })
+        .collect();
+    let no_new_privileges = process.no_new_privileges().unwrap_or(false);
+
+    // An unprivileged agent, e.g. one running directly on a host, cannot switch users or groups.
+    // Running the process as the agent's user and group is allowed only if they are not more
+    // privileged than the requested ones.
+    let euid = unsafe { libc::geteuid() };
+    let egid = unsafe { libc::getegid() };
+    let switch_user = euid == 0;
+    if !switch_user && uid != 0 && uid != euid {
+        return Err(Error::Unsupported(format!(
+            "uid {} in an unprivileged agent",
+            uid
+        )));
+    }
+    if !switch_user && gid != 0 && gid != egid {
+        return Err(Error::Unsupported(format!(
+            "gid {} in an unprivileged agent",
+            gid
+        )));
+    }
+    if !switch_user && !additional_gids.is_empty() {
+        return Err(Error::Unsupported(
+            "additionalGids in an unprivileged agent".to_string(),
+        ));
+    }
+    #[cfg(not(target_os = "linux"))]
+    if no_new_privileges {
+        return Err(Error::Unsupported("noNewPrivileges".to_string()));
+    }
+
+    // SAFETY: Only async-signal-safe functions are called between fork and exec.
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, rlimit) in &rlimits {
+                check(libc::setrlimit(*resource as _, rlimit))?;
+            }
+            if let Some(umask) = umask {
+                libc::umask(umask);
+            }
+            if switch_user {
+                check(libc::setgroups(
+                    additional_gids.len() as _,
+                    additional_gids.as_ptr(),
+                ))?;
+                check(libc::setgid(gid))?;
+                check(libc::setuid(uid))?;
+            }
+            #[cfg(target_os = "linux")]
+            if no_new_privileges {
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
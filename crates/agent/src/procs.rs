@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Snapshot of the processes in the guest, used to find the processes of containers.
+// The processes of a container are its init and exec processes and their descendants.
+// Descendants that have been orphaned and reparented are no longer counted.
+
+use std::{collections::HashMap, io, time::Duration};
+
+use libakari::container_rpc::ContainerStats;
+
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    // Name of the executable, which may be truncated.
+    pub command: String,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    // Resident set size in bytes.
+    pub rss: u64,
+}
+
+#[cfg(target_os = "linux")]
+pub fn list() -> io::Result<Vec<ProcessInfo>> {
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let mut processes = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let Some(pid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // The process may exit while the processes are listed.
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            continue;
+        };
+        if let Some(process) = parse_stat(pid, &stat, ticks, page_size) {
+            processes.push(process);
+        }
+    }
+    Ok(processes)
+}
+
+// Return the start time of a process, which tells it apart from a later process with the same
+// pid. It is in clock ticks since boot.
+#[cfg(target_os = "linux")]
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+// Parse /proc/<pid>/stat. The command name may contain spaces and parentheses, so the fields are
+// split after its last closing parenthesis, starting from the third field (state).
+#[cfg(target_os = "linux")]
+fn parse_stat(pid: u32, stat: &str, ticks: u64, page_size: u64) -> Option<ProcessInfo> {
+    let (head, fields) = stat.rsplit_once(')')?;
+    let (_, command) = head.split_once('(')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+    let ticks_to_duration = |t: u64| Duration::from_nanos(t * 1_000_000_000 / ticks);
+    Some(ProcessInfo {
+        pid,
+        ppid: field(4)? as u32,
+        command: command.to_string(),
+        user_time: ticks_to_duration(field(14)?),
+        system_time: ticks_to_duration(field(15)?),
+        rss: field(24)? * page_size,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn list() -> io::Result<Vec<ProcessInfo>> {
+    use std::mem::size_of;
+
+    let count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Leave room for the processes spawned in between.
+    let mut pids: Vec<libc::pid_t> = vec![0; count as usize + 64];
+    let count = unsafe {
+        libc::proc_listallpids(
+            pids.as_mut_ptr().cast(),
+            (pids.len() * size_of::<libc::pid_t>()) as libc::c_int,
+        )
+    };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    pids.truncate(count as usize);
+
+    let timebase = timebase();
+    let mut processes = Vec::new();
+    for pid in pids {
+        let mut bsd_info = unsafe { std::mem::zeroed::<libc::proc_bsdinfo>() };
+        let mut task_info = unsafe { std::mem::zeroed::<libc::proc_taskinfo>() };
+        // The process may exit while the processes are listed.
+        let read = unsafe {
+            libc::proc_pidinfo(
+                pid,
+                libc::PROC_PIDTBSDINFO,
+                0,
+                (&mut bsd_info as *mut libc::proc_bsdinfo).cast(),
+                size_of::<libc::proc_bsdinfo>() as libc::c_int,
+            ) == size_of::<libc::proc_bsdinfo>() as libc::c_int
+                && libc::proc_pidinfo(
+                    pid,
+                    libc::PROC_PIDTASKINFO,
+                    0,
+                    (&mut task_info as *mut libc::proc_taskinfo).cast(),
+                    size_of::<libc::proc_taskinfo>() as libc::c_int,
+                ) == size_of::<libc::proc_taskinfo>() as libc::c_int
+        };
+        if !read {
+            continue;
+        }
+        // The name is longer than the command, but is empty for some processes.
+        let name = match c_string(&bsd_info.pbi_name) {
+            name if name.is_empty() => c_string(&bsd_info.pbi_comm),
+            name => name,
+        };
+        processes.push(ProcessInfo {
+            pid: pid as u32,
+            ppid: bsd_info.pbi_ppid,
+            command: name,
+            user_time: timebase(task_info.pti_total_user),
+            system_time: timebase(task_info.pti_total_system),
+            rss: task_info.pti_resident_size,
+        });
+    }
+    Ok(processes)
+}
+
+// Return the start time of a process, which tells it apart from a later process with the same
+// pid. It is in microseconds since the epoch.
+#[cfg(not(target_os = "linux"))]
+pub fn start_time(pid: u32) -> Option<u64> {
+    use std::mem::size_of;
+
+    let mut bsd_info = unsafe { std::mem::zeroed::<libc::proc_bsdinfo>() };
+    let read = unsafe {
+        libc::proc_pidinfo(
+            pid as libc::c_int,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            (&mut bsd_info as *mut libc::proc_bsdinfo).cast(),
+            size_of::<libc::proc_bsdinfo>() as libc::c_int,
+        )
+    };
+    if read != size_of::<libc::proc_bsdinfo>() as libc::c_int {
+        return None;
+    }
+    Some(bsd_info.pbi_start_tvsec * 1_000_000 + bsd_info.pbi_start_tvusec)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn c_string(chars: &[libc::c_char]) -> String {
+    let bytes: Vec<u8> = chars
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// The CPU times are in Mach absolute time units, which are not nanoseconds on Apple silicon.
+#[cfg(not(target_os = "linux"))]
+#[allow(deprecated)]
+fn timebase() -> impl Fn(u64) -> Duration {
+    let mut info = libc::mach_timebase_info { numer: 0, denom: 0 };
+    if unsafe { libc::mach_timebase_info(&mut info) } != 0 || info.denom == 0 {
+        info = libc::mach_timebase_info { numer: 1, denom: 1 };
+    }
+    move |t| Duration::from_nanos((t as u128 * info.numer as u128 / info.denom as u128) as u64)
+}
+
+// Select the processes in the trees rooted at the given pids.
+pub fn descendants<'a>(processes: &'a [ProcessInfo], roots: &[u32]) -> Vec<&'a ProcessInfo> {
+    let mut children: HashMap<u32, Vec<&ProcessInfo>> = HashMap::new();
+    for process in processes {
+        children.entry(process.ppid).or_default().push(process);
+    }
+    let mut selected: Vec<&ProcessInfo> = processes
+        .iter()
+        .filter(|process| roots.contains(&process.pid))
+        .collect();
+    let mut i = 0;
+    while i < selected.len() {
+        if let Some(children) = children.get(&selected[i].pid) {
+            selected.extend(children.iter().filter(|child| !roots.contains(&child.pid)));
+        }
+        i += 1;
+    }
+    selected
+}
+
+// Sum up the resource usage of the processes in the trees rooted at the given pids.
+pub fn stats(roots: &[u32]) -> io::Result<ContainerStats> {
+    let processes = list()?;
+    let processes = descendants(&processes, roots);
+    Ok(ContainerStats {
+        cpu_user_ns: processes
+            .iter()
+            .map(|process| process.user_time.as_nanos() as u64)
+            .sum(),
+        cpu_system_ns: processes
+            .iter()
+            .map(|process| process.system_time.as_nanos() as u64)
+            .sum(),
+        rss_bytes: processes.iter().map(|process| process.rss).sum(),
+        process_count: processes.len() as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, ppid: u32) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            command: String::new(),
+            user_time: Duration::ZERO,
+            system_time: Duration::ZERO,
+            rss: 0,
+        }
+    }
+
+    fn pids(processes: Vec<&ProcessInfo>) -> Vec<u32> {
+        processes.iter().map(|process| process.pid).collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_stat_with_parentheses_in_command() {
+        let stat = "42 (a) (b c) S 7 42 42 0 -1 4194304 100 0 0 0 250 50 0 0 20 0 1 0 123456 \
+                    1000000 300 18446744073709551615\n";
+        let process = parse_stat(42, stat, 100, 4096).unwrap();
+        assert_eq!(process.pid, 42);
+        assert_eq!(process.ppid, 7);
+        assert_eq!(process.command, "a) (b c");
+        assert_eq!(process.user_time, Duration::from_millis(2500));
+        assert_eq!(process.system_time, Duration::from_millis(500));
+        assert_eq!(process.rss, 300 * 4096);
+
+        assert!(parse_stat(42, "42 (sh S 7", 100, 4096).is_none());
+        assert!(parse_stat(42, "42 (sh) S 7 42", 100, 4096).is_none());
+    }
+
+    #[test]
+    fn list_current_process() {
+        let pid = std::process::id();
+        let processes = list().unwrap();
+        let current = processes.iter().find(|process| process.pid == pid).unwrap();
+        assert_eq!(current.ppid, unsafe { libc::getppid() } as u32);
+
+        let started = start_time(pid);
+        assert!(started.is_some());
+        assert_eq!(start_time(pid), started);
+        assert_eq!(start_time(u32::MAX), None);
+    }
+
+    #[test]
+    fn select_descendants() {
+        // 1 ─┬─ 2 ── 4 ── 5
+        //    └─ 3 ── 6
+        // 7 ── 8
+        let processes = [
+            process(1, 0),
+            process(2, 1),
+            process(3, 1),
+            process(4, 2),
+            process(5, 4),
+            process(6, 3),
+            process(7, 0),
+            process(8, 7),
+        ];
+        assert_eq!(pids(descendants(&processes, &[2, 7])), [2, 7, 4, 8, 5]);
+        assert_eq!(pids(descendants(&processes, &[1])), [1, 2, 3, 4, 6, 5]);
+        // A root in the tree of another root is listed once.
+        assert_eq!(pids(descendants(&processes, &[2, 4])), [2, 4, 5]);
+        // The roots that have exited are skipped.
+        assert_eq!(pids(descendants(&processes, &[9, 3])), [3, 6]);
+        assert!(descendants(&processes, &[]).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    process::{Command, Stdio},
+};
+
+// Pseudo-terminal of a process with `process.terminal` set.
+// It is allocated when the process is created so that it can be resized before it starts.
+pub struct Pty {
+    master: OwnedFd,
+    slave: Option<OwnedFd>,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty succeeded, so both fds are open and owned by us.
+        let (master, slave) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        Ok(Self {
+            master,
+            slave: Some(slave),
+        })
+    }
+
+    pub fn master(&self) -> &OwnedFd {
+        &self.master
+    }
+
+    // Make the slave the stdio and the controlling terminal of the process.
+    // The slave is moved into the command, so the agent closes it when the command is dropped
+    // and reading the master fails once the process closes it.
+    pub fn configure(&mut self, cmd: &mut Command) -> io::Result<()> {
+        let slave = self
+            .slave
+            .take()
+            .ok_or_else(|| io::Error::other("pty is already in use"))?;
+        cmd.stdin(Stdio::from(slave.try_clone()?));
+        cmd.stdout(Stdio::from(slave.try_clone()?));
+        cmd.stderr(Stdio::from(slave));
+        // SAFETY: Only async-signal-safe functions are called between fork and exec.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    pub fn resize(&self, width: u32, height: u32) -> io::Result<()> {
+        let size = libc::winsize {
+            ws_row: height as u16,
+            ws_col: width as u16,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ as _, &size) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{collections::HashSet, io, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use tokio::{signal::unix::Signal, sync::Mutex};
+
+use crate::container::ContainerRegistry;
+
+// Make the agent the reaper of the orphaned descendants of the container processes.
+#[cfg(target_os = "linux")]
+pub fn set_subreaper() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Orphans are reparented to launchd on macOS, which has no subreaper.
+#[cfg(not(target_os = "linux"))]
+pub fn set_subreaper() -> io::Result<()> {
+    Ok(())
+}
+
+// Interval to check whether the processes adopted from a previous agent have exited.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Reap the exited container processes on every SIGCHLD and record their exit status.
+// The registry is locked while reaping, so a process is always registered before its exit.
+// The adopted processes are not children of the agent, and are polled instead.
+// Only the container processes and the orphans are reaped, since the other children, e.g. the
+// hooks and the mount helpers, are waited for by the code that spawns them.
+pub async fn reap(mut sigchld: Signal, registry: Arc<Mutex<ContainerRegistry>>) -> Result<()> {
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            signal = sigchld.recv() => {
+                if signal.is_none() {
+                    anyhow::bail!("SIGCHLD stream closed");
+                }
+                let mut registry = registry.lock().await;
+                let pids = registry.child_pids();
+                for pid in &pids {
+                    if let Some(exit_code) = wait_pid(*pid)? {
+                        registry.exited(*pid, exit_code);
+                    }
+                }
+                reap_orphans(&pids)?;
+            }
+            _ = poll.tick() => registry.lock().await.poll_adopted(),
+        }
+    }
+}
+
+// Reap the orphaned descendants of the container processes, which are reparented to the agent
+// as the subreaper. They are told apart from the other children of the agent by their process
+// group, since the container processes are spawned in their own groups.
+#[cfg(target_os = "linux")]
+fn reap_orphans(container_pids: &HashSet<u32>) -> io::Result<()> {
+    let agent_pid = std::process::id();
+    let agent_group = unsafe { libc::getpgrp() };
+    for process in crate::procs::list()? {
+        if process.ppid != agent_pid || container_pids.contains(&process.pid) {
+            continue;
+        }
+        if unsafe { libc::getpgid(process.pid as libc::pid_t) } == agent_group {
+            continue;
+        }
+        // The orphan may have exited and been reaped in between.
+        if let Err(e) = wait_pid(process.pid) {
+            log::debug!("Failed to reap orphan {}: {}", process.pid, e);
+        }
+    }
+    Ok(())
+}
+
+// Orphans are not reparented to the agent on macOS.
+#[cfg(not(target_os = "linux"))]
+fn reap_orphans(_container_pids: &HashSet<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+// Reap a child if it has exited, and return its exit code.
+fn wait_pid(pid: u32) -> io::Result<Option<i32>> {
+    let mut status = 0;
+    match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+        0 => Ok(None),
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ECHILD) {
+                Ok(None)
+            } else {
+                Err(e)
+            }
+        }
+        _ => Ok(Some(exit_code(status))),
+    }
+}
+
+// Convert a wait status to a shell-style exit code.
+fn exit_code(status: libc::c_int) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        0
+    }
+}
@@ -0,0 +1,417 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    ffi::CString,
+    io,
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use libakari::validate::{mount_kind, MountKind, ValidationError, ValidationErrors};
+use oci_spec::runtime::{Mount, Root};
+use serde::{Deserialize, Serialize};
+
+use crate::container::Error;
+
+// Whether the agent can chroot and mount. An unprivileged agent, e.g. one running directly on a
+// host, can only run the processes in the root of the host, whose files are used as they are.
+pub fn privileged() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn unprivileged(what: &str) -> Error {
+    Error::Unsupported(format!("{} in an unprivileged agent", what))
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn mount(
+    source: &Path,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> io::Result<()> {
+    let source = cstring(source)?;
+    let target = cstring(target)?;
+    let fstype = fstype.map(CString::new).transpose()?;
+    let data = data.map(CString::new).transpose()?;
+    check(unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype
+                .as_ref()
+                .map_or(std::ptr::null(), |fstype| fstype.as_ptr()),
+            flags,
+            data.as_ref()
+                .map_or(std::ptr::null(), |data| data.as_ptr().cast()),
+        )
+    })
+}
+
+// Flags of the mount options other than the kind and the size.
+#[cfg(target_os = "linux")]
+fn mount_flags(options: &[String]) -> libc::c_ulong {
+    options
+        .iter()
+        .fold(0, |flags, option| match option.as_str() {
+            "ro" => flags | libc::MS_RDONLY,
+            "nosuid" => flags | libc::MS_NOSUID,
+            "nodev" => flags | libc::MS_NODEV,
+            "noexec" => flags | libc::MS_NOEXEC,
+            _ => flags,
+        })
+}
+
+#[cfg(target_os = "linux")]
+fn bind(source: &Path, target: &Path, options: &[String]) -> io::Result<()> {
+    let recursive = options.iter().any(|option| option == "rbind");
+    let flags = if recursive {
+        libc::MS_BIND | libc::MS_REC
+    } else {
+        libc::MS_BIND
+    };
+    mount(source, target, None, flags, None)?;
+    // The flags of a bind mount can be changed only by remounting it.
+    let flags = mount_flags(options);
+    if flags != 0 {
+        let remount = mount(
+            target,
+            target,
+            None,
+            libc::MS_BIND | libc::MS_REMOUNT | flags,
+            None,
+        );
+        if let Err(e) = remount {
+            let _ = unmount(target);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn tmpfs(target: &Path, options: &[String]) -> io::Result<()> {
+    let size = options.iter().find(|option| option.starts_with("size="));
+    mount(
+        Path::new("tmpfs"),
+        target,
+        Some("tmpfs"),
+        mount_flags(options),
+        size.map(String::as_str),
+    )
+}
+
+// Options passed to the mount commands of macOS with -o.
+#[cfg(not(target_os = "linux"))]
+fn mount_options(options: &[String]) -> Option<String> {
+    let options: Vec<&str> = options
+        .iter()
+        .map(String::as_str)
+        .filter(|option| ["ro", "nosuid", "nodev", "noexec"].contains(option))
+        .collect();
+    (!options.is_empty()).then(|| options.join(","))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_mount(cmd: &mut Command) -> io::Result<()> {
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+// macOS has no bind mounts, so the source is mounted with nullfs.
+#[cfg(not(target_os = "linux"))]
+fn bind(source: &Path, target: &Path, options: &[String]) -> io::Result<()> {
+    let mut cmd = Command::new("/sbin/mount_nullfs");
+    if let Some(options) = mount_options(options) {
+        cmd.arg("-o").arg(options);
+    }
+    run_mount(cmd.arg(source).arg(target))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tmpfs(target: &Path, options: &[String]) -> io::Result<()> {
+    let mut cmd = Command::new("/sbin/mount_tmpfs");
+    if let Some(options) = mount_options(options) {
+        cmd.arg("-o").arg(options);
+    }
+    if let Some(size) = options
+        .iter()
+        .find_map(|option| option.strip_prefix("size="))
+    {
+        cmd.arg("-s").arg(size);
+    }
+    run_mount(cmd.arg(target))
+}
+
+fn invalid(error: ValidationError) -> Error {
+    ValidationErrors(vec![error]).into()
+}
+
+// Create the mount point of a source in the rootfs, which is a file if the source is a file.
+fn create_mount_point(source: Option<&Path>, target: &Path) -> io::Result<()> {
+    if target.exists() {
+        return Ok(());
+    }
+    match source {
+        Some(source) if !source.is_dir() => {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(target)?;
+        }
+        _ => std::fs::create_dir_all(target)?,
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn unmount(target: &Path) -> io::Result<()> {
+    let target = cstring(target)?;
+    check(unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount(target: &Path) -> io::Result<()> {
+    let target = cstring(target)?;
+    check(unsafe { libc::unmount(target.as_ptr(), 0) })
+}
+
+// Root filesystem of a container, into which its processes are chrooted.
+// The device and system filesystems are mounted under it until it is unmounted explicitly, so
+// that the mounts are kept for the next agent when the agent exits.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rootfs {
+    path: PathBuf,
+    // Mounts made by the agent, in the order they were made.
+    mounts: Vec<PathBuf>,
+}
+
+impl Rootfs {
+    pub fn prepare(root: &Root, mounts: &[Mount], bundle: &Path) -> Result<Self, Error> {
+        let path = bundle.join(root.path()).canonicalize()?;
+        let mut rootfs = Self {
+            path,
+            mounts: Vec::new(),
+        };
+        if !privileged() {
+            if rootfs.path != Path::new("/") {
+                return Err(unprivileged("root other than /"));
+            }
+            if root.readonly().unwrap_or(false) {
+                return Err(unprivileged("root.readonly"));
+            }
+            if !mounts.is_empty() {
+                return Err(unprivileged("mounts"));
+            }
+            return Ok(rootfs);
+        }
+        if let Err(e) = rootfs.mount_all(root.readonly().unwrap_or(false), mounts, bundle) {
+            rootfs.unmount();
+            return Err(e);
+        }
+        Ok(rootfs)
+    }
+
+    // Unmount all the mounts in the reverse order.
+    pub fn unmount(&mut self) {
+        while let Some(target) = self.mounts.pop() {
+            if let Err(e) = unmount(&target) {
+                log::error!("Failed to unmount {:?}: {}", target, e);
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Return whether a path stays in the rootfs, following the symbolic links in the part of it
+    // that exists.
+    fn contains(&self, target: &Path) -> io::Result<bool> {
+        let existing = target
+            .ancestors()
+            .find(|path| path.exists())
+            .unwrap_or(&self.path)
+            .canonicalize()?;
+        Ok(existing.starts_with(&self.path))
+    }
+
+    // Mount a file over an absolute path in the container, creating the mount point if it does
+    // not exist. The mount is unmounted with the rootfs.
+    pub fn mount_file(&mut self, source: &Path, path: &Path) -> Result<(), Error> {
+        if !privileged() {
+            return Err(unprivileged("mounts"));
+        }
+        let outside = || Error::PathOutsideRootfs(path.to_path_buf());
+        let target = self.path.join(path.strip_prefix("/").unwrap_or(path));
+        // A dangling symbolic link may point out of the rootfs, where the mount point would be
+        // created.
+        if !self.contains(&target)? || (target.is_symlink() && !target.exists()) {
+            return Err(outside());
+        }
+        create_mount_point(Some(source), &target)?;
+        let target = target.canonicalize()?;
+        if !target.starts_with(&self.path) {
+            return Err(outside());
+        }
+        bind(source, &target, &[])?;
+        self.mounts.push(target);
+        Ok(())
+    }
+
+    // Mount devfs on /dev. macOS has no procfs or sysfs.
+    // A read-only root is supported only if it is on a read-only filesystem such as a read-only
+    // share, since macOS cannot remount a subtree.
+    #[cfg(not(target_os = "linux"))]
+    fn mount_all(&mut self, readonly: bool, mounts: &[Mount], bundle: &Path) -> Result<(), Error> {
+        if readonly {
+            let path = cstring(&self.path)?;
+            let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+            check(unsafe { libc::statvfs(path.as_ptr(), &mut stat) })?;
+            if stat.f_flag & libc::ST_RDONLY == 0 {
+                return Err(Error::Unsupported(
+                    "root.readonly on a writable filesystem".to_string(),
+                ));
+            }
+        }
+
+        let dev = self.path.join("dev");
+        std::fs::create_dir_all(&dev)?;
+        let fstype = CString::new("devfs").unwrap();
+        let target = cstring(&dev)?;
+        check(unsafe { libc::mount(fstype.as_ptr(), target.as_ptr(), 0, std::ptr::null_mut()) })?;
+        self.mounts.push(dev);
+        self.mount_spec(mounts, bundle)
+    }
+
+    // Bind the root to itself so that it can be remounted read-only, and bind /dev and /sys
+    // and mount procfs under it.
+    #[cfg(target_os = "linux")]
+    fn mount_all(&mut self, readonly: bool, mounts: &[Mount], bundle: &Path) -> Result<(), Error> {
+        if readonly {
+            mount(
+                &self.path,
+                &self.path,
+                None,
+                libc::MS_BIND | libc::MS_REC,
+                None,
+            )?;
+            self.mounts.push(self.path.clone());
+        }
+
+        let targets = [
+            (Path::new("/dev"), "dev", None, libc::MS_BIND | libc::MS_REC),
+            (Path::new("proc"), "proc", Some("proc"), 0),
+            (Path::new("/sys"), "sys", None, libc::MS_BIND | libc::MS_REC),
+        ];
+        for (source, target, fstype, flags) in targets {
+            let target = self.path.join(target);
+            std::fs::create_dir_all(&target)?;
+            mount(source, &target, fstype, flags, None)?;
+            self.mounts.push(target);
+        }
+        self.mount_spec(mounts, bundle)?;
+
+        if readonly {
+            mount(
+                &self.path,
+                &self.path,
+                None,
+                libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Mount spec.mounts in order. The sources of bind mounts are paths in the guest, which the
+    // server has translated from the host paths under the VM shares.
+    fn mount_spec(&mut self, mounts: &[Mount], bundle: &Path) -> Result<(), Error> {
+        for mount in mounts {
+            let destination = mount.destination();
+            let kind = mount_kind(mount).ok_or_else(|| {
+                let typ = mount.typ().clone().unwrap_or_default();
+                invalid(ValidationError::UnsupportedMountType(
+                    destination.clone(),
+                    typ,
+                ))
+            })?;
+            let source = match kind {
+                MountKind::Bind => {
+                    let source = mount.source().as_ref().ok_or_else(|| {
+                        invalid(ValidationError::MountSourceNotSpecified(
+                            destination.clone(),
+                        ))
+                    })?;
+                    Some(bundle.join(source))
+                }
+                MountKind::Tmpfs => None,
+            };
+            let target = self
+                .path
+                .join(destination.strip_prefix("/").unwrap_or(destination));
+            // Do not follow a symbolic link out of the rootfs.
+            if !self.contains(&target)? {
+                return Err(invalid(ValidationError::InvalidMountDestination(
+                    destination.clone(),
+                )));
+            }
+            create_mount_point(source.as_deref(), &target)?;
+            let target = target.canonicalize()?;
+
+            let options = mount.options().as_deref().unwrap_or_default();
+            match &source {
+                Some(source) => bind(source, &target, options)?,
+                None => tmpfs(&target, options)?,
+            }
+            log::debug!("Mounted {:?} on {:?}", source, target);
+            self.mounts.push(target);
+        }
+        Ok(())
+    }
+}
+
+// Change the root of the command to `root` and the working directory to `cwd` in it.
+// This must be configured before the privileges are dropped. An unprivileged agent only changes
+// the working directory, since the root can only be `/`.
+pub fn configure(cmd: &mut Command, root: &Path, cwd: &Path) -> Result<(), Error> {
+    if !privileged() {
+        if root != Path::new("/") {
+            return Err(unprivileged("root other than /"));
+        }
+        cmd.current_dir(cwd);
+        return Ok(());
+    }
+    let root = cstring(root)?;
+    let cwd = cstring(cwd)?;
+    // SAFETY: Only async-signal-safe functions are called between fork and exec.
+    unsafe {
+        cmd.pre_exec(move || {
+            check(libc::chroot(root.as_ptr()))?;
+            check(libc::chdir(cwd.as_ptr()))
+        });
+    }
+    Ok(())
+}
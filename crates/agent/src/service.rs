@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use containerd_shim::{
+    api::{
+        ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest,
+        Empty, ExecProcessRequest, KillRequest, PidsRequest, PidsResponse, ResizePtyRequest,
+        StartRequest, StartResponse, StateRequest, StateResponse, StatsRequest, StatsResponse,
+        Status, WaitRequest, WaitResponse,
+    },
+    protos::{
+        cgroups::metrics::{CPUStat, CPUUsage, MemoryEntry, MemoryStat, Metrics, PidsStat},
+        protobuf::{
+            well_known_types::{any::Any, timestamp::Timestamp},
+            Message, MessageField,
+        },
+        types::task::ProcessInfo,
+    },
+    DeleteResponse, Task as ShimTask, TtrpcContext, TtrpcResult,
+};
+use libakari::container_rpc::{
+    self, ContainerCommand, ContainerState, ContainerStats, ContainerStatus, CreateRequest,
+    ErrorCode, ExecRequest, Stdio, PROCESS_DETAILS_METADATA, PROCESS_DETAILS_TYPE_URL,
+    STATS_TYPE_URL,
+};
+use oci_spec::runtime::Spec;
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+
+use crate::container::{self, ContainerRegistry, Error};
+
+impl From<Error> for ttrpc::Error {
+    fn from(e: Error) -> Self {
+        let code = match e.code() {
+            ErrorCode::AlreadyExists => ttrpc::Code::ALREADY_EXISTS,
+            ErrorCode::NotFound => ttrpc::Code::NOT_FOUND,
+            ErrorCode::FailedPrecondition => ttrpc::Code::FAILED_PRECONDITION,
+            ErrorCode::InvalidArgument => ttrpc::Code::INVALID_ARGUMENT,
+            ErrorCode::Internal => ttrpc::Code::INTERNAL,
+        };
+        ttrpc::Error::RpcStatus(ttrpc::get_status(code, e.to_string()))
+    }
+}
+
+// Use the ttrpc stream ID as the request ID.
+fn request_id(ctx: &TtrpcContext) -> u64 {
+    ctx.mh.stream_id as u64
+}
+
+// An empty exec ID refers to the init process of the container.
+fn exec_id(exec_id: &str) -> Option<String> {
+    if exec_id.is_empty() {
+        None
+    } else {
+        Some(exec_id.to_string())
+    }
+}
+
+// Convert the stats to the cgroups v1 metrics of containerd. The guest has no cgroups for the
+// containers, so only the usage summed over their processes is filled in, and no limits.
+fn metrics(stats: &ContainerStats) -> Metrics {
+    let usage = CPUUsage {
+        total: stats.cpu_user_ns + stats.cpu_system_ns,
+        kernel: stats.cpu_system_ns,
+        user: stats.cpu_user_ns,
+        ..Default::default()
+    };
+    let memory = MemoryStat {
+        rss: stats.rss_bytes,
+        total_rss: stats.rss_bytes,
+        usage: MessageField::some(MemoryEntry {
+            usage: stats.rss_bytes,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    Metrics {
+        pids: MessageField::some(PidsStat {
+            current: stats.process_count as u64,
+            ..Default::default()
+        }),
+        cpu: MessageField::some(CPUStat {
+            usage: MessageField::some(usage),
+            ..Default::default()
+        }),
+        memory: MessageField::some(memory),
+        ..Default::default()
+    }
+}
+
+// The server replaces the stdio paths of the task requests with vsock URIs.
+fn stdio(stdin: &str, stdout: &str, stderr: &str) -> TtrpcResult<Stdio> {
+    Stdio::from_uris(stdin, stdout, stderr).map_err(|e| {
+        ttrpc::Error::RpcStatus(ttrpc::get_status(
+            ttrpc::Code::INVALID_ARGUMENT,
+            e.to_string(),
+        ))
+    })
+}
+
+fn task_status(status: ContainerStatus) -> Status {
+    match status {
+        ContainerStatus::Created => Status::CREATED,
+        ContainerStatus::Running => Status::RUNNING,
+        ContainerStatus::Stopped => Status::STOPPED,
+    }
+}
+
+fn timestamp(time: Option<SystemTime>) -> MessageField<Timestamp> {
+    MessageField::from_option(time.map(Timestamp::from))
+}
+
+// Serves the containerd shim v2 task API on top of the container registry.
+#[derive(Clone)]
+pub struct AgentService {
+    registry: Arc<Mutex<ContainerRegistry>>,
+}
+
+impl AgentService {
+    pub fn new(registry: Arc<Mutex<ContainerRegistry>>) -> Self {
+        Self { registry }
+    }
+
+    async fn handle_cmd(&self, cmd: ContainerCommand) -> TtrpcResult<ContainerState> {
+        Ok(container::handle_cmd(&self.registry, cmd).await?)
+    }
+}
+
+#[async_trait]
+impl ShimTask for AgentService {
+    async fn connect(
+        &self,
+        ctx: &TtrpcContext,
+        req: ConnectRequest,
+    ) -> TtrpcResult<ConnectResponse> {
+        let state = self
+            .handle_cmd(ContainerCommand::State(container_rpc::StateRequest {
+                request_id: request_id(ctx),
+                container_id: req.id,
+                exec_id: None,
+            }))
+            .await?;
+        Ok(ConnectResponse {
+            shim_pid: std::process::id(),
+            task_pid: state.pid.unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    async fn create(
+        &self,
+        ctx: &TtrpcContext,
+        req: CreateTaskRequest,
+    ) -> TtrpcResult<CreateTaskResponse> {
+        let spec_path = PathBuf::from(req.bundle()).join("config.json");
+        let spec = Spec::load(&spec_path).map_err(|e| {
+            ttrpc::Error::Others(format!("Failed to load {}: {}", spec_path.display(), e))
+        })?;
+        let stdio = stdio(&req.stdin, &req.stdout, &req.stderr)?;
+        let state = self
+            .handle_cmd(ContainerCommand::Create(CreateRequest {
+                request_id: request_id(ctx),
+                container_id: req.id,
+                bundle: PathBuf::from(req.bundle),
+                spec: Box::new(spec),
+                stdio,
+            }))
+            .await?;
+        Ok(CreateTaskResponse {
+            pid: state.pid.unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    async fn delete(&self, ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        let state = self
+            .handle_cmd(ContainerCommand::Delete(container_rpc::DeleteRequest {
+                request_id: request_id(ctx),
+                exec_id: exec_id(req.exec_id()),
+                container_id: req.id,
+            }))
+            .await?;
+        Ok(DeleteResponse {
+            pid: state.pid.unwrap_or_default(),
+            exit_status: state.exit_code.unwrap_or_default() as u32,
+            exited_at: timestamp(state.exited_at),
+            ..Default::default()
+        })
+    }
+
+    async fn exec(&self, ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        // containerd passes the OCI process spec of the exec process as JSON.
+        let process = req
+            .spec
+            .as_ref()
+            .ok_or_else(|| ttrpc::Error::Others("Process spec is not specified".to_string()))?;
+        let process = serde_json::from_slice(&process.value)
+            .map_err(|e| ttrpc::Error::Others(format!("Invalid process spec: {}", e)))?;
+        let stdio = stdio(&req.stdin, &req.stdout, &req.stderr)?;
+        self.handle_cmd(ContainerCommand::Exec(ExecRequest {
+            request_id: request_id(ctx),
+            container_id: req.id,
+            exec_id: req.exec_id,
+            process: Box::new(process),
+            stdio,
+        }))
+        .await?;
+        Ok(Empty::new())
+    }
+
+    async fn kill(&self, ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+        self.handle_cmd(ContainerCommand::Kill(container_rpc::KillRequest {
+            request_id: request_id(ctx),
+            exec_id: exec_id(req.exec_id()),
+            signal: req.signal,
+            all: req.all,
+            container_id: req.id,
+        }))
+        .await?;
+        Ok(Empty::new())
+    }
+
+    async fn pids(&self, ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        let pids = self.registry.lock().await.pids(&req.id)?;
+        let mut processes = Vec::new();
+        for (pid, details) in pids {
+            if !ctx.metadata.contains_key(PROCESS_DETAILS_METADATA) {
+                processes.push(ProcessInfo {
+                    pid,
+                    ..Default::default()
+                });
+                continue;
+            }
+            let value = serde_json::to_vec(&details).map_err(|e| {
+                ttrpc::Error::Others(format!("Failed to encode process details: {}", e))
+            })?;
+            processes.push(ProcessInfo {
+                pid,
+                info: MessageField::some(Any {
+                    type_url: PROCESS_DETAILS_TYPE_URL.to_string(),
+                    value,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        Ok(PidsResponse {
+            processes,
+            ..Default::default()
+        })
+    }
+
+    async fn resize_pty(&self, ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        self.handle_cmd(ContainerCommand::ResizePty(
+            container_rpc::ResizePtyRequest {
+                request_id: request_id(ctx),
+                exec_id: exec_id(req.exec_id()),
+                width: req.width,
+                height: req.height,
+                container_id: req.id,
+            },
+        ))
+        .await?;
+        Ok(Empty::new())
+    }
+
+    async fn start(&self, ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+        let state = self
+            .handle_cmd(ContainerCommand::Start(container_rpc::StartRequest {
+                request_id: request_id(ctx),
+                exec_id: exec_id(req.exec_id()),
+                container_id: req.id,
+            }))
+            .await?;
+        Ok(StartResponse {
+            pid: state.pid.unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    async fn state(&self, ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+        let state = self
+            .handle_cmd(ContainerCommand::State(container_rpc::StateRequest {
+                request_id: request_id(ctx),
+                exec_id: exec_id(req.exec_id()),
+                container_id: req.id,
+            }))
+            .await?;
+        Ok(StateResponse {
+            id: state.id,
+            exec_id: state.exec_id.unwrap_or_default(),
+            pid: state.pid.unwrap_or_default(),
+            status: task_status(state.status).into(),
+            exit_status: state.exit_code.unwrap_or_default() as u32,
+            exited_at: timestamp(state.exited_at),
+            ..Default::default()
+        })
+    }
+
+    async fn stats(&self, _ctx: &TtrpcContext, req: StatsRequest) -> TtrpcResult<StatsResponse> {
+        let stats = self.registry.lock().await.stats(&req.id)?;
+        let value = metrics(&stats)
+            .write_to_bytes()
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to encode stats: {}", e)))?;
+        Ok(StatsResponse {
+            stats: MessageField::some(Any {
+                type_url: STATS_TYPE_URL.to_string(),
+                value,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    async fn wait(&self, ctx: &TtrpcContext, req: WaitRequest) -> TtrpcResult<WaitResponse> {
+        let exec_id = exec_id(req.exec_id());
+        let state_cmd = || {
+            ContainerCommand::State(container_rpc::StateRequest {
+                request_id: request_id(ctx),
+                container_id: req.id.clone(),
+                exec_id: exec_id.clone(),
+            })
+        };
+        // Subscribe before checking the state so that no exit is missed in between.
+        let (mut state, mut exits) = {
+            let mut registry = self.registry.lock().await;
+            let exits = registry.subscribe();
+            (registry.state(&req.id, exec_id.as_deref())?, exits)
+        };
+        while state.status != ContainerStatus::Stopped {
+            match exits.recv().await {
+                Ok(event) if event.container_id == req.id && event.exec_id == exec_id => {
+                    state.exit_code = Some(event.exit_code);
+                    state.exited_at = Some(event.exited_at);
+                    break;
+                }
+                Ok(_) => {}
+                // Some exits were dropped, so check the state again.
+                Err(RecvError::Lagged(_)) => state = self.handle_cmd(state_cmd()).await?,
+                Err(RecvError::Closed) => {
+                    return Err(ttrpc::Error::Others("Exit events closed".to_string()))
+                }
+            }
+        }
+        Ok(WaitResponse {
+            exit_status: state.exit_code.unwrap_or_default() as u32,
+            exited_at: timestamp(state.exited_at),
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Persistent state of the containers, so that a restarted agent can take them over.
+// The state of each container is saved to `<id>.json` in the state directory. It is written to
+// a temporary file and renamed, so that a crash never leaves a partially written state behind.
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+const STATE_SUFFIX: &str = ".json";
+const TEMP_SUFFIX: &str = ".json.tmp";
+
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn path(&self, id: &str, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, suffix))
+    }
+
+    pub fn save<T: Serialize>(&self, id: &str, state: &T) -> io::Result<()> {
+        let path = self.path(id, STATE_SUFFIX);
+        let temp_path = self.path(id, TEMP_SUFFIX);
+        let mut file = File::create(&temp_path)?;
+        serde_json::to_writer(&mut file, state)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)
+    }
+
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id, STATE_SUFFIX)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    // Load all the saved states. Unreadable states are skipped, and the temporary files of
+    // interrupted writes are removed.
+    pub fn load_all<T: DeserializeOwned>(&self) -> io::Result<Vec<(String, T)>> {
+        let mut states = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.ends_with(TEMP_SUFFIX) {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let Some(id) = name.strip_suffix(STATE_SUFFIX) else {
+                continue;
+            };
+            let state = fs::read(&path)
+                .map_err(serde_json::Error::io)
+                .and_then(|data| serde_json::from_slice(&data));
+            match state {
+                Ok(state) => states.push((id.to_string(), state)),
+                Err(e) => log::error!("Failed to load the state in {:?}: {}", path, e),
+            }
+        }
+        Ok(states)
+    }
+}
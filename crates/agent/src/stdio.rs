@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    fs::File,
+    io,
+    os::fd::OwnedFd,
+    pin::Pin,
+    process::{Child, Command, Stdio as ProcessStdio},
+    task::{Context, Poll},
+};
+
+use libakari::container_rpc::Stdio;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::transport::{Listener, Transport};
+
+// Listeners for the stdio streams of a process.
+// They are bound when the process is created so that the host can connect before it starts.
+#[derive(Default)]
+pub struct StdioListeners {
+    stdin: Option<Box<dyn Listener>>,
+    stdout: Option<Box<dyn Listener>>,
+    stderr: Option<Box<dyn Listener>>,
+}
+
+impl StdioListeners {
+    pub fn bind(transport: &Transport, stdio: &Stdio) -> io::Result<Self> {
+        let bind = |port: Option<u32>| port.map(|port| transport.bind(port)).transpose();
+        Ok(Self {
+            stdin: bind(stdio.stdin)?,
+            stdout: bind(stdio.stdout)?,
+            stderr: bind(stdio.stderr)?,
+        })
+    }
+
+    // Pipe the streams that have a listener and close the others.
+    pub fn configure(&self, cmd: &mut Command) {
+        let stdio = |listener: &Option<Box<dyn Listener>>| match listener {
+            Some(_) => ProcessStdio::piped(),
+            None => ProcessStdio::null(),
+        };
+        cmd.stdin(stdio(&self.stdin));
+        cmd.stdout(stdio(&self.stdout));
+        cmd.stderr(stdio(&self.stderr));
+    }
+
+    // Forward the pipes of the spawned process to the connections from the host.
+    pub fn forward(self, child: &mut Child) -> io::Result<()> {
+        if let (Some(listener), Some(stdin)) = (self.stdin, child.stdin.take()) {
+            let stdin = tokio::process::ChildStdin::from_std(stdin)?;
+            tokio::spawn(forward_input(listener, stdin));
+        }
+        if let (Some(listener), Some(stdout)) = (self.stdout, child.stdout.take()) {
+            let stdout = tokio::process::ChildStdout::from_std(stdout)?;
+            tokio::spawn(forward_output(listener, stdout));
+        }
+        if let (Some(listener), Some(stderr)) = (self.stderr, child.stderr.take()) {
+            let stderr = tokio::process::ChildStderr::from_std(stderr)?;
+            tokio::spawn(forward_output(listener, stderr));
+        }
+        Ok(())
+    }
+
+    // Forward the pty master of the spawned process instead of pipes.
+    // The terminal merges stdout and stderr, so the stderr listener is not used.
+    pub fn forward_pty(self, master: OwnedFd) -> io::Result<()> {
+        if let Some(listener) = self.stdin {
+            let writer = tokio::fs::File::from_std(File::from(master.try_clone()?));
+            tokio::spawn(forward_input(listener, writer));
+        }
+        if let Some(listener) = self.stdout {
+            let reader = PtyMaster(tokio::fs::File::from_std(File::from(master)));
+            tokio::spawn(forward_output(listener, reader));
+        }
+        Ok(())
+    }
+}
+
+// Reading the pty master fails with EIO once every slave fd is closed, which means the end of
+// the output.
+struct PtyMaster(tokio::fs::File);
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) if e.raw_os_error() == Some(libc::EIO) => Poll::Ready(Ok(())),
+            poll => poll,
+        }
+    }
+}
+
+async fn forward_input<W: AsyncWrite + Unpin>(listener: Box<dyn Listener>, mut writer: W) {
+    let result = async {
+        let mut stream = listener.accept().await?;
+        tokio::io::copy(&mut stream, &mut writer).await?;
+        // Close the pipe so that the process sees EOF.
+        writer.shutdown().await
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to forward stdin: {}", e);
+    }
+}
+
+async fn forward_output<R: AsyncRead + Unpin>(listener: Box<dyn Listener>, mut reader: R) {
+    let result = async {
+        let mut stream = listener.accept().await?;
+        tokio::io::copy(&mut reader, &mut stream).await?;
+        stream.shutdown().await
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to forward output: {}", e);
+    }
+}
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    os::{
+        fd::{FromRawFd, IntoRawFd},
+        unix::net,
+    },
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener, UnixStream},
+};
+use vsock::{VsockAddr, VsockListener, VMADDR_CID_ANY};
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[async_trait]
+pub trait Listener: Send + Sync {
+    async fn accept(&self) -> io::Result<Box<dyn Stream>>;
+}
+
+struct VsockTransportListener(AsyncFd<VsockListener>);
+
+#[async_trait]
+impl Listener for VsockTransportListener {
+    async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            let (stream, addr) = match guard.try_io(|inner| inner.get_ref().accept()) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+            log::debug!("Accepted a vsock connection from {}", addr);
+            stream.set_nonblocking(true)?;
+            // vsock streams can be read and written like Unix domain sockets.
+            let stream = unsafe { net::UnixStream::from_raw_fd(stream.into_raw_fd()) };
+            return Ok(Box::new(UnixStream::from_std(stream)?));
+        }
+    }
+}
+
+struct UnixTransportListener(UnixListener);
+
+#[async_trait]
+impl Listener for UnixTransportListener {
+    async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        let (stream, _) = self.0.accept().await?;
+        Ok(Box::new(stream))
+    }
+}
+
+struct TcpTransportListener(TcpListener);
+
+#[async_trait]
+impl Listener for TcpTransportListener {
+    async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        let (stream, addr) = self.0.accept().await?;
+        log::debug!("Accepted a TCP connection from {}", addr);
+        Ok(Box::new(stream))
+    }
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum TransportKind {
+    /// vsock, used inside the VM
+    Vsock,
+    /// Unix domain sockets named after the port in the socket directory
+    Unix,
+    /// TCP on the loopback interface
+    Tcp,
+}
+
+// Maps the vsock ports used by the agent to the listeners of the selected transport.
+#[derive(Clone, Debug)]
+pub struct Transport {
+    kind: TransportKind,
+    socket_dir: PathBuf,
+}
+
+impl Transport {
+    pub fn new(kind: TransportKind, socket_dir: PathBuf) -> Self {
+        Self { kind, socket_dir }
+    }
+
+    pub fn unix_path(&self, port: u32) -> PathBuf {
+        self.socket_dir.join(format!("{}.sock", port))
+    }
+
+    pub fn bind(&self, port: u32) -> io::Result<Box<dyn Listener>> {
+        log::info!("Listening on {:?} port {}", self.kind, port);
+        match self.kind {
+            TransportKind::Vsock => {
+                let listener = VsockListener::bind(&VsockAddr::new(VMADDR_CID_ANY, port))?;
+                listener.set_nonblocking(true)?;
+                Ok(Box::new(VsockTransportListener(AsyncFd::new(listener)?)))
+            }
+            TransportKind::Unix => {
+                let path = self.unix_path(port);
+                if path.exists() {
+                    std::fs::remove_file(&path)?;
+                }
+                Ok(Box::new(UnixTransportListener(UnixListener::bind(path)?)))
+            }
+            TransportKind::Tcp => {
+                let port = u16::try_from(port).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "TCP port out of range")
+                })?;
+                let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+                let listener = std::net::TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Ok(Box::new(TcpTransportListener(TcpListener::from_std(
+                    listener,
+                )?)))
+            }
+        }
+    }
+}
@@ -5,7 +5,11 @@ pub mod connect;
 pub mod create;
 pub mod delete;
 pub mod error;
+pub mod events;
+pub mod exec;
 pub mod kill;
+pub mod port;
+pub mod ps;
 pub mod spec;
 pub mod start;
 pub mod state;
@@ -6,6 +6,7 @@ use containerd_shim::{
     protos::shim::{shim::CreateTaskRequest, shim_ttrpc_async::TaskClient},
     Context,
 };
+use libakari::validate::validate_spec;
 use liboci_cli::Create;
 
 use super::error::Error;
@@ -16,20 +17,20 @@ pub async fn create(args: Create, client: &TaskClient) -> Result<(), Error> {
         return Err(Error::ContainerConfigDoesNotExist);
     }
     let spec: oci_spec::runtime::Spec = serde_json::from_str(&std::fs::read_to_string(spec_path)?)?;
+    validate_spec(&spec)?;
 
-    // TODO: Needs to convert to the guest path
-    let _rootfs_path = if let Some(root) = spec.root() {
-        if root.path().is_relative() {
-            args.bundle.join(root.path()).canonicalize()?
-        } else {
-            root.path().canonicalize()?
-        }
+    // The server stages the bundle and the rootfs in the shared directory of the VM.
+    // Check that the rootfs exists here to fail early.
+    if let Some(root) = spec.root() {
+        args.bundle.join(root.path()).canonicalize()?;
     } else {
         return Err(Error::RootfsPathIsNotSpecified);
-    };
+    }
 
-    // TODO: Needs to convert to the guest path
-    let bundle = args.bundle.to_str().unwrap();
+    // The server runs in a different working directory.
+    let bundle = args.bundle.canonicalize()?;
+    let bundle = bundle.to_str().unwrap();
+    // The server sends the master of a pty to the console socket.
     let (terminal, stdin, stdout) = match args.console_socket {
         Some(ref console_socket) => (
             true,
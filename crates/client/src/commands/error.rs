@@ -7,6 +7,24 @@ pub enum Error {
     ContainerConfigDoesNotExist,
     #[error("Root path is not specified")]
     RootfsPathIsNotSpecified,
+    #[error("Command is not specified")]
+    CommandIsNotSpecified,
+    #[error("Invalid stats in the response")]
+    InvalidStats,
+    #[error("Invalid signal: {0}")]
+    InvalidSignal(String),
+    #[error("Invalid process info in the response")]
+    InvalidProcessInfo,
+    #[error("Invalid format: {0}")]
+    InvalidFormat(String),
+    #[error("Unsupported ps options: {0}")]
+    UnsupportedPsOptions(String),
+    #[error("Ports connection closed by the server")]
+    PortsConnectionClosed,
+    #[error("{0}")]
+    Ports(String),
+    #[error(transparent)]
+    InvalidSpec(#[from] libakari::validate::ValidationErrors),
     #[error(transparent)]
     VmConfig(#[from] libakari::vm_config::Error),
     #[error(transparent)]
@@ -14,6 +32,8 @@ pub enum Error {
     #[error(transparent)]
     Api(#[from] libakari::vm_rpc::Error),
     #[error(transparent)]
+    Rpc(#[from] libakari::container_rpc::Error),
+    #[error(transparent)]
     Deserialize(#[from] serde_json::Error),
     #[error(transparent)]
     RpcClient(#[from] ttrpc::Error),
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::time::Duration;
+
+use anyhow::Result;
+use containerd_shim::{
+    api::{StateRequest, StatsRequest, Status},
+    protos::{cgroups::metrics::Metrics, protobuf::Message, shim_async::TaskClient},
+    Context,
+};
+use libakari::container_rpc::{ContainerStats, STATS_TYPE_URL};
+use liboci_cli::Events;
+use serde::Serialize;
+
+use super::error::Error;
+
+// Event printed as a JSON line, in the same form as runc.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Event {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    id: String,
+    data: ContainerStats,
+}
+
+async fn stats(client: &TaskClient, id: &str) -> Result<ContainerStats, Error> {
+    let ctx = Context::default();
+    let req = StatsRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    let response = client.stats(ctx, &req).await.map_err(Error::RpcClient)?;
+    let stats = response
+        .stats
+        .as_ref()
+        .filter(|stats| stats.type_url == STATS_TYPE_URL)
+        .ok_or(Error::InvalidStats)?;
+    let metrics = Metrics::parse_from_bytes(&stats.value).map_err(|_| Error::InvalidStats)?;
+    Ok(ContainerStats {
+        cpu_user_ns: metrics.cpu.usage.user,
+        cpu_system_ns: metrics.cpu.usage.kernel,
+        rss_bytes: metrics.memory.rss,
+        process_count: metrics.pids.current as u32,
+    })
+}
+
+async fn stopped(client: &TaskClient, id: &str) -> Result<bool, Error> {
+    let ctx = Context::default();
+    let req = StateRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    let response = client.state(ctx, &req).await.map_err(Error::RpcClient)?;
+    Ok(response.status.unwrap() == Status::STOPPED)
+}
+
+// Print the resource usage of a container once with `--stats`, or every interval until the
+// container stops otherwise.
+pub async fn events(args: Events, client: &TaskClient) -> Result<(), Error> {
+    let interval = Duration::from_secs(args.interval as u64);
+    loop {
+        let event = Event {
+            typ: "stats",
+            id: args.container_id.clone(),
+            data: stats(client, &args.container_id).await?,
+        };
+        println!("{}", serde_json::to_string(&event)?);
+        if args.stats || stopped(client, &args.container_id).await? {
+            return Ok(());
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use anyhow::Result;
+use containerd_shim::{
+    api::{ExecProcessRequest, StartRequest, WaitRequest},
+    protos::{
+        protobuf::{well_known_types::any::Any, MessageField},
+        shim_async::TaskClient,
+    },
+    Context,
+};
+use liboci_cli::Exec;
+use oci_spec::runtime::{Process, User};
+
+use super::error::Error;
+
+const PROCESS_TYPE_URL: &str = "types.containerd.io/opencontainers/runtime-spec/1/Process";
+
+// Build the process spec from the process file or the command line arguments.
+fn process(args: &Exec) -> Result<Process, Error> {
+    if let Some(ref process_path) = args.process {
+        return Ok(serde_json::from_str(&std::fs::read_to_string(
+            process_path,
+        )?)?);
+    }
+
+    if args.command.is_empty() {
+        return Err(Error::CommandIsNotSpecified);
+    }
+
+    let mut process = Process::default();
+    process.set_args(Some(args.command.clone()));
+    if let Some(ref cwd) = args.cwd {
+        process.set_cwd(cwd.clone());
+    }
+    let env = process
+        .env()
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .chain(args.env.iter().map(|(k, v)| format!("{}={}", k, v)))
+        .collect();
+    process.set_env(Some(env));
+    process.set_terminal(Some(args.tty));
+    process.set_no_new_privileges(Some(args.no_new_privs));
+    if let Some((uid, gid)) = args.user {
+        let mut user = User::default();
+        user.set_uid(uid);
+        user.set_gid(gid.unwrap_or(0));
+        if !args.additional_gids.is_empty() {
+            user.set_additional_gids(Some(args.additional_gids.clone()));
+        }
+        process.set_user(user);
+    }
+
+    Ok(process)
+}
+
+pub async fn exec(args: Exec, client: &TaskClient) -> Result<(), Error> {
+    let process = process(&args)?;
+    let exec_id = format!("exec-{}", std::process::id());
+
+    // The server sends the master of a pty to the console socket.
+    let console_socket = args
+        .console_socket
+        .as_ref()
+        .map(|console_socket| console_socket.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let ctx = Context::default();
+    let req = ExecProcessRequest {
+        id: args.container_id.clone(),
+        exec_id: exec_id.clone(),
+        terminal: args.tty,
+        stdin: console_socket.clone(),
+        stdout: console_socket,
+        spec: MessageField::some(Any {
+            type_url: PROCESS_TYPE_URL.to_string(),
+            value: serde_json::to_vec(&process)?,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let _ = client.exec(ctx, &req).await.map_err(Error::RpcClient)?;
+
+    let ctx = Context::default();
+    let req = StartRequest {
+        id: args.container_id.clone(),
+        exec_id: exec_id.clone(),
+        ..Default::default()
+    };
+    let response = client.start(ctx, &req).await.map_err(Error::RpcClient)?;
+
+    if let Some(pid_file) = args.pid_file {
+        std::fs::write(pid_file, response.pid.to_string())?;
+    }
+
+    if args.detach {
+        return Ok(());
+    }
+
+    // Wait for the process to exit and exit with the same status.
+    let ctx = Context::default();
+    let req = WaitRequest {
+        id: args.container_id,
+        exec_id,
+        ..Default::default()
+    };
+    let response = client.wait(ctx, &req).await.map_err(Error::RpcClient)?;
+    std::process::exit(response.exit_status as i32);
+}
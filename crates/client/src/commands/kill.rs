@@ -7,12 +7,137 @@ use liboci_cli::Kill;
 
 use super::error::Error;
 
+// The number of signals on macOS, including the null signal.
+const NSIG: u32 = 32;
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    #[cfg(target_os = "macos")]
+    ("EMT", libc::SIGEMT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    #[cfg(target_os = "macos")]
+    ("INFO", libc::SIGINFO),
+    ("SYS", libc::SIGSYS),
+];
+
+// Parse a signal given as a number, or as a name with or without the "SIG" prefix, e.g. "9",
+// "KILL" or "SIGKILL". The guest is macOS like the host, so the numbers are the same in both.
+fn parse_signal(signal: &str) -> Result<u32, Error> {
+    if let Ok(number) = signal.parse::<u32>() {
+        if number == 0 || number >= NSIG {
+            return Err(Error::InvalidSignal(signal.to_string()));
+        }
+        return Ok(number);
+    }
+    let name = signal.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, number)| *number as u32)
+        .ok_or_else(|| Error::InvalidSignal(signal.to_string()))
+}
+
 pub async fn kill(args: Kill, client: &TaskClient) -> Result<(), Error> {
     let ctx = Context::default();
     let req = KillRequest {
         id: args.container_id,
+        signal: parse_signal(&args.signal)?,
+        all: args.all,
         ..Default::default()
     };
     let _ = client.kill(ctx, &req).await.map_err(Error::RpcClient)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(signal: &str) -> Option<u32> {
+        parse_signal(signal).ok()
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse("1"), Some(1));
+        assert_eq!(parse("9"), Some(9));
+        assert_eq!(parse(&(NSIG - 1).to_string()), Some(NSIG - 1));
+    }
+
+    #[test]
+    fn parse_names() {
+        for (name, number) in SIGNALS {
+            let number = Some(*number as u32);
+            assert_eq!(parse(name), number, "{}", name);
+            assert_eq!(parse(&format!("SIG{}", name)), number, "SIG{}", name);
+            assert_eq!(parse(&name.to_ascii_lowercase()), number, "{}", name);
+        }
+        assert_eq!(parse("SIGTERM"), Some(libc::SIGTERM as u32));
+        assert_eq!(parse("sigkill"), Some(libc::SIGKILL as u32));
+        assert_eq!(parse("Hup"), Some(libc::SIGHUP as u32));
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn parse_macos_signals() {
+        assert_eq!(parse("EMT"), Some(7));
+        assert_eq!(parse("SIGINFO"), Some(29));
+    }
+
+    #[test]
+    fn reject_invalid_signals() {
+        for signal in [
+            "0",
+            &NSIG.to_string(),
+            "64",
+            "-9",
+            "",
+            "SIG",
+            "SIGSIGKILL",
+            "KILL ",
+            "RTMIN",
+            "unknown",
+        ] {
+            assert!(
+                matches!(parse_signal(signal), Err(Error::InvalidSignal(s)) if s == signal),
+                "{:?}",
+                signal
+            );
+        }
+    }
+
+    #[test]
+    fn names_are_unique_and_in_range() {
+        for (i, (name, number)) in SIGNALS.iter().enumerate() {
+            assert!((1..NSIG as libc::c_int).contains(number), "{}", name);
+            assert!(SIGNALS[..i].iter().all(|(n, _)| n != name), "{}", name);
+        }
+    }
+}
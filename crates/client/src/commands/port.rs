@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use libakari::{
+    container_rpc,
+    path::ports_sock_path,
+    ports::{PortCommand, PortMapping, PortResponse},
+};
+use tokio::net::UnixStream;
+
+use super::error::Error;
+
+/// Manage the host ports published to a container
+#[derive(Parser, Debug)]
+pub struct Port {
+    #[clap(subcommand)]
+    cmd: PortCmd,
+}
+
+#[derive(Subcommand, Debug)]
+enum PortCmd {
+    /// Publish a host port to a container port, e.g. 8080:80 or 127.0.0.1:8080:80
+    Add {
+        container_id: String,
+        /// [hostIp:]hostPort:containerPort
+        mapping: PortMapping,
+    },
+    /// List the published ports of a container
+    Ls { container_id: String },
+    /// Unpublish a host port
+    Rm {
+        container_id: String,
+        host_port: u16,
+    },
+}
+
+pub async fn port(args: Port, root_path: &Path) -> Result<(), Error> {
+    let cmd = match args.cmd {
+        PortCmd::Add {
+            container_id,
+            mapping,
+        } => PortCommand::Add {
+            container_id,
+            mapping,
+        },
+        PortCmd::Ls { container_id } => PortCommand::List { container_id },
+        PortCmd::Rm {
+            container_id,
+            host_port,
+        } => PortCommand::Remove {
+            container_id,
+            host_port,
+        },
+    };
+
+    let mut stream = UnixStream::connect(ports_sock_path(root_path)).await?;
+    container_rpc::write_message_async(&mut stream, &cmd).await?;
+    let res = container_rpc::read_message_async(&mut stream)
+        .await?
+        .ok_or(Error::PortsConnectionClosed)?;
+    match res {
+        PortResponse::Ok(mappings) => {
+            for mapping in mappings {
+                println!("{}", mapping);
+            }
+            Ok(())
+        }
+        PortResponse::Error(e) => Err(Error::Ports(e)),
+    }
+}
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::time::Duration;
+
+use anyhow::Result;
+use containerd_shim::{api::PidsRequest, protos::shim_async::TaskClient, Context};
+use libakari::container_rpc::{ProcessDetails, PROCESS_DETAILS_METADATA, PROCESS_DETAILS_TYPE_URL};
+use liboci_cli::Ps;
+use serde::Serialize;
+
+use super::error::Error;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Process {
+    pid: u32,
+    #[serde(flatten)]
+    details: ProcessDetails,
+}
+
+// Format a CPU time as `ps` does: [DD-]HH:MM:SS.
+fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    match days {
+        0 => time,
+        days => format!("{}-{}", days, time),
+    }
+}
+
+fn print_table(processes: &[Process]) {
+    println!(
+        "{:<8} {:<8} {:<16} {:<11} CMD",
+        "PID", "PPID", "EXEC", "TIME"
+    );
+    for process in processes {
+        println!(
+            "{:<8} {:<8} {:<16} {:<11} {}",
+            process.pid,
+            process.details.ppid,
+            process.details.exec_id.as_deref().unwrap_or("-"),
+            format_time(Duration::from_nanos(process.details.cpu_time_ns)),
+            process.details.command
+        );
+    }
+}
+
+// List the processes of a container. The processes run in the guest, so they are listed by the
+// agent instead of the host `ps`, and `ps` options are not supported.
+pub async fn ps(args: Ps, client: &TaskClient) -> Result<(), Error> {
+    if !args.ps_options.is_empty() {
+        return Err(Error::UnsupportedPsOptions(args.ps_options.join(" ")));
+    }
+    let mut ctx = Context::default();
+    ctx.add(PROCESS_DETAILS_METADATA.to_string(), "true".to_string());
+    let req = PidsRequest {
+        id: args.container_id,
+        ..Default::default()
+    };
+    let response = client.pids(ctx, &req).await.map_err(Error::RpcClient)?;
+    let mut processes = Vec::new();
+    for process in &response.processes {
+        let details = process
+            .info
+            .as_ref()
+            .filter(|info| info.type_url == PROCESS_DETAILS_TYPE_URL)
+            .ok_or(Error::InvalidProcessInfo)?;
+        processes.push(Process {
+            pid: process.pid,
+            details: serde_json::from_slice(&details.value)?,
+        });
+    }
+
+    match args.format.as_str() {
+        "table" => print_table(&processes),
+        "json" => println!("{}", serde_json::to_string(&processes)?),
+        format => return Err(Error::InvalidFormat(format.to_string())),
+    }
+    Ok(())
+}
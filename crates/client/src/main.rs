@@ -11,13 +11,17 @@ use containerd_shim::protos::shim::shim_ttrpc_async::TaskClient;
 use liboci_cli::StandardCmd;
 use ttrpc::asynchronous::Client;
 
-use commands::{connect, create, delete, kill, spec, start, state};
+use commands::{connect, create, delete, events, exec, kill, port, ps, spec, start, state};
 use libakari::path::{aux_sock_path, root_path};
 
 #[derive(clap::Parser, Debug)]
 pub enum CommonCmd {
     Spec(liboci_cli::Spec),
     Connect(connect::Connect),
+    Events(liboci_cli::Events),
+    Exec(liboci_cli::Exec),
+    Port(port::Port),
+    Ps(liboci_cli::Ps),
 }
 
 // The OCI Command Line Interface document doesn't define any global
@@ -83,6 +87,10 @@ async fn main() -> Result<()> {
         SubCommand::Common(cmd) => match *cmd {
             CommonCmd::Spec(spec) => spec::spec(spec)?,
             CommonCmd::Connect(connect) => connect::connect(connect, &client).await?,
+            CommonCmd::Events(events) => events::events(events, &client).await?,
+            CommonCmd::Exec(exec) => exec::exec(exec, &client).await?,
+            CommonCmd::Port(port) => port::port(port, &root_path).await?,
+            CommonCmd::Ps(ps) => ps::ps(ps, &client).await?,
         },
     };
 
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Annotations in config.json that configure a container beyond the OCI runtime spec.
+// List values are separated by commas.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{
+    ports::PortMapping,
+    validate::{valid_hostname, ValidationError},
+};
+
+// Extra entries of /etc/hosts in the form of "name:address", e.g. "db:192.168.64.10".
+pub const HOSTS: &str = "org.akari.hosts";
+// Name servers of /etc/resolv.conf. Those of the VM are used if not specified.
+pub const DNS: &str = "org.akari.dns";
+// Search domains of /etc/resolv.conf. Those of the VM are used if not specified.
+pub const DNS_SEARCH: &str = "org.akari.dns.search";
+// Options of /etc/resolv.conf, e.g. "ndots:2". Those of the VM are used if not specified.
+pub const DNS_OPTIONS: &str = "org.akari.dns.options";
+
+// Host ports published to the container in the form of "[hostIp:]hostPort:containerPort",
+// e.g. "8080:80" or "127.0.0.1:8443:443".
+pub const PORTS: &str = "org.akari.ports";
+
+// Name resolution settings of a container. Empty lists are filled in from the VM.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsConfig {
+    pub hosts: Vec<(String, IpAddr)>,
+    pub nameservers: Vec<IpAddr>,
+    pub search: Vec<String>,
+    pub options: Vec<String>,
+}
+
+fn list<'a>(annotations: &'a HashMap<String, String>, key: &str) -> Vec<&'a str> {
+    annotations
+        .get(key)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn invalid(key: &str, value: &str) -> ValidationError {
+    ValidationError::InvalidAnnotation(key.to_string(), value.to_string())
+}
+
+fn parse_host(host: &str) -> Option<(String, IpAddr)> {
+    let (name, address) = host.split_once(':')?;
+    if !valid_hostname(name) {
+        return None;
+    }
+    Some((name.to_string(), address.parse().ok()?))
+}
+
+// Read the name resolution settings from the annotations of a container.
+pub fn dns_config(annotations: &HashMap<String, String>) -> Result<DnsConfig, ValidationError> {
+    let hosts = list(annotations, HOSTS)
+        .into_iter()
+        .map(|host| parse_host(host).ok_or_else(|| invalid(HOSTS, host)))
+        .collect::<Result<_, _>>()?;
+    let nameservers = list(annotations, DNS)
+        .into_iter()
+        .map(|nameserver| nameserver.parse().map_err(|_| invalid(DNS, nameserver)))
+        .collect::<Result<_, _>>()?;
+    let search = list(annotations, DNS_SEARCH)
+        .into_iter()
+        .map(|domain| match valid_hostname(domain) {
+            true => Ok(domain.to_string()),
+            false => Err(invalid(DNS_SEARCH, domain)),
+        })
+        .collect::<Result<_, _>>()?;
+    let options = list(annotations, DNS_OPTIONS)
+        .into_iter()
+        .map(|option| match option.contains(char::is_whitespace) {
+            false => Ok(option.to_string()),
+            true => Err(invalid(DNS_OPTIONS, option)),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(DnsConfig {
+        hosts,
+        nameservers,
+        search,
+        options,
+    })
+}
+
+// Read the host ports published to a container from its annotations.
+pub fn ports(annotations: &HashMap<String, String>) -> Result<Vec<PortMapping>, ValidationError> {
+    list(annotations, PORTS)
+        .into_iter()
+        .map(|mapping| mapping.parse().map_err(|_| invalid(PORTS, mapping)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_dns_config() {
+        let config = dns_config(&annotations(&[
+            (HOSTS, "db:192.168.64.10, cache:2001:db8::1,"),
+            (DNS, "1.1.1.1,2001:db8::53"),
+            (DNS_SEARCH, "example.com, corp.example"),
+            (DNS_OPTIONS, "ndots:2,edns0"),
+        ]));
+        assert_eq!(
+            config,
+            Ok(DnsConfig {
+                hosts: vec![
+                    ("db".to_string(), "192.168.64.10".parse().unwrap()),
+                    ("cache".to_string(), "2001:db8::1".parse().unwrap()),
+                ],
+                nameservers: vec!["1.1.1.1".parse().unwrap(), "2001:db8::53".parse().unwrap()],
+                search: vec!["example.com".to_string(), "corp.example".to_string()],
+                options: vec!["ndots:2".to_string(), "edns0".to_string()],
+            })
+        );
+        assert_eq!(dns_config(&HashMap::new()), Ok(DnsConfig::default()));
+    }
+
+    #[test]
+    fn reject_invalid_dns_config() {
+        for (key, value, invalid_item) in [
+            (HOSTS, "db", "db"),
+            (HOSTS, "db:not-an-address", "db:not-an-address"),
+            (HOSTS, "-db:10.0.0.1", "-db:10.0.0.1"),
+            (DNS, "1.1.1.1,dns.example", "dns.example"),
+            (DNS_SEARCH, "under_score.example", "under_score.example"),
+            (DNS_OPTIONS, "ndots: 2", "ndots: 2"),
+        ] {
+            assert_eq!(
+                dns_config(&annotations(&[(key, value)])),
+                Err(invalid(key, invalid_item)),
+                "{}={}",
+                key,
+                value
+            );
+        }
+    }
+}
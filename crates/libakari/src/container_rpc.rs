@@ -1,14 +1,520 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
-use serde::{Deserialize, Serialize};
+// Messages exchanged between the host and the agent.
+// Each message is a JSON payload preceded by an 8-byte header:
+// protocol version (u16 BE), reserved (u16, zero) and payload length (u32 BE).
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// vsock port on which the agent serves the containerd shim v2 task API.
+pub const AGENT_VSOCK_PORT: u32 = 9999;
+// vsock port on which the agent pushes framed `ExitEvent`s.
+pub const AGENT_EVENTS_VSOCK_PORT: u32 = 9997;
+// vsock port on which the agent accepts the connections to the published ports. Each connection
+// starts with a framed `PortConnectRequest` and `PortConnectResponse`, and then carries the data.
+pub const AGENT_PORTS_VSOCK_PORT: u32 = 9996;
+
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 8;
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Invalid message header")]
+    InvalidHeader,
+    #[error("Message too large: {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("Invalid stdio URI: {0}")]
+    InvalidStdioUri(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+}
+
+const VSOCK_URI_SCHEME: &str = "vsock://";
+
+// Return the URI that the server puts in a task request for a stdio stream on a vsock port.
+pub fn vsock_uri(port: u32) -> String {
+    format!("{}{}", VSOCK_URI_SCHEME, port)
+}
+
+// vsock ports that carry the stdio streams of a process. A stream without a port is closed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stdio {
+    pub stdin: Option<u32>,
+    pub stdout: Option<u32>,
+    pub stderr: Option<u32>,
+}
+
+impl Stdio {
+    // Parse the stdio fields of a task request. Each field is either empty or a vsock URI.
+    pub fn from_uris(stdin: &str, stdout: &str, stderr: &str) -> Result<Self, Error> {
+        let parse = |uri: &str| -> Result<Option<u32>, Error> {
+            if uri.is_empty() {
+                return Ok(None);
+            }
+            uri.strip_prefix(VSOCK_URI_SCHEME)
+                .and_then(|port| port.parse().ok())
+                .map(Some)
+                .ok_or_else(|| Error::InvalidStdioUri(uri.to_string()))
+        };
+        Ok(Self {
+            stdin: parse(stdin)?,
+            stdout: parse(stdout)?,
+            stderr: parse(stderr)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateRequest {
+    pub request_id: u64,
+    pub container_id: String,
+    // Path of the bundle staged by the server in the shared directory.
+    pub bundle: PathBuf,
+    pub spec: Box<oci_spec::runtime::Spec>,
+    pub stdio: Stdio,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteRequest {
+    pub request_id: u64,
+    pub container_id: String,
+    pub exec_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecRequest {
+    pub request_id: u64,
+    pub container_id: String,
+    pub exec_id: String,
+    pub process: Box<oci_spec::runtime::Process>,
+    pub stdio: Stdio,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KillRequest {
+    pub request_id: u64,
+    pub container_id: String,
+    pub exec_id: Option<String>,
+    pub signal: u32,
+    // Send the signal to all processes in the container.
+    pub all: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResizePtyRequest {
+    pub request_id: u64,
+    pub container_id: String,
+    pub exec_id: Option<String>,
+    pub width: u32,
+    pub height: u32,
+}
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartRequest {
+    pub request_id: u64,
+    pub container_id: String,
+    pub exec_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateRequest {
+    pub request_id: u64,
+    pub container_id: String,
+    pub exec_id: Option<String>,
+}
+
+// Command on the containers of the agent, translated from a task API request.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum ContainerCommand {
-    Create(Box<oci_spec::runtime::Spec>),
-    Delete,
-    Kill,
-    Start,
-    State,
+    Create(CreateRequest),
+    Delete(DeleteRequest),
+    Exec(ExecRequest),
+    Kill(KillRequest),
+    ResizePty(ResizePtyRequest),
+    Start(StartRequest),
+    State(StateRequest),
+}
+
+impl ContainerCommand {
+    pub fn request_id(&self) -> u64 {
+        match self {
+            ContainerCommand::Create(req) => req.request_id,
+            ContainerCommand::Delete(req) => req.request_id,
+            ContainerCommand::Exec(req) => req.request_id,
+            ContainerCommand::Kill(req) => req.request_id,
+            ContainerCommand::ResizePty(req) => req.request_id,
+            ContainerCommand::Start(req) => req.request_id,
+            ContainerCommand::State(req) => req.request_id,
+        }
+    }
+
+    pub fn container_id(&self) -> &str {
+        match self {
+            ContainerCommand::Create(req) => &req.container_id,
+            ContainerCommand::Delete(req) => &req.container_id,
+            ContainerCommand::Exec(req) => &req.container_id,
+            ContainerCommand::Kill(req) => &req.container_id,
+            ContainerCommand::ResizePty(req) => &req.container_id,
+            ContainerCommand::Start(req) => &req.container_id,
+            ContainerCommand::State(req) => &req.container_id,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContainerStatus {
+    Created,
+    Running,
+    Stopped,
+}
+
+// State of a container, or of an exec process if `exec_id` is set, reported by the agent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerState {
+    pub id: String,
+    pub exec_id: Option<String>,
+    pub status: ContainerStatus,
+    pub pid: Option<u32>,
+    pub exit_code: Option<i32>,
+    pub exited_at: Option<SystemTime>,
+}
+
+// Notification pushed from the agent when a process of a container exits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExitEvent {
+    pub container_id: String,
+    pub exec_id: Option<String>,
+    pub pid: u32,
+    pub exit_code: i32,
+    pub exited_at: SystemTime,
+}
+
+// Request to connect a tunnel to a port on the localhost of the guest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortConnectRequest {
+    pub container_id: String,
+    pub port: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PortConnectResponse {
+    Connected,
+    Error(String),
+}
+
+// Type URL of the `Any` in the response of the Stats RPC, which holds the `ContainerStats` as the
+// cgroups v1 `Metrics` of containerd, so that containerd and its clients can decode it.
+pub const STATS_TYPE_URL: &str = "io.containerd.cgroups.v1.Metrics";
+
+// Resource usage of the processes of a container, sampled in the guest.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStats {
+    pub cpu_user_ns: u64,
+    pub cpu_system_ns: u64,
+    pub rss_bytes: u64,
+    pub process_count: u32,
+}
+
+// Type URL of the `Any` in each process in the response of the Pids RPC, which holds a
+// `ProcessDetails` as JSON.
+pub const PROCESS_DETAILS_TYPE_URL: &str = "akari.v1.ProcessDetails";
+// ttrpc metadata key of a Pids request that asks for the `ProcessDetails`. They are left out
+// otherwise, since containerd cannot decode the type and fails to list the processes.
+pub const PROCESS_DETAILS_METADATA: &str = "akari-process-details";
+
+// Details of a process of a container, listed in the guest.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessDetails {
+    pub ppid: u32,
+    // The exec process that the process belongs to, or `None` for the init process.
+    pub exec_id: Option<String>,
+    pub command: String,
+    // User and system CPU time.
+    pub cpu_time_ns: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    AlreadyExists,
+    NotFound,
+    FailedPrecondition,
+    InvalidArgument,
+    Internal,
+}
+
+fn encode_header(len: usize) -> Result<[u8; HEADER_SIZE], Error> {
+    if len > MAX_MESSAGE_SIZE {
+        return Err(Error::MessageTooLarge(len));
+    }
+    let mut header = [0; HEADER_SIZE];
+    header[0..2].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    header[4..8].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(header)
+}
+
+fn decode_header(header: &[u8; HEADER_SIZE]) -> Result<usize, Error> {
+    let version = u16::from_be_bytes([header[0], header[1]]);
+    if version != PROTOCOL_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    if header[2..4] != [0, 0] {
+        return Err(Error::InvalidHeader);
+    }
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(Error::MessageTooLarge(len));
+    }
+    Ok(len)
+}
+
+// Encode a message into a frame.
+pub fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>, Error> {
+    let payload = serde_json::to_vec(msg)?;
+    let header = encode_header(payload.len())?;
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Decode a message from a complete frame.
+pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> Result<T, Error> {
+    let header: &[u8; HEADER_SIZE] = frame
+        .get(..HEADER_SIZE)
+        .and_then(|h| h.try_into().ok())
+        .ok_or(Error::InvalidHeader)?;
+    let len = decode_header(header)?;
+    let payload = frame
+        .get(HEADER_SIZE..HEADER_SIZE + len)
+        .ok_or(Error::InvalidHeader)?;
+    Ok(serde_json::from_slice(payload)?)
+}
+
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> Result<(), Error> {
+    writer.write_all(&encode(msg)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Return an error for a connection closed in the middle of a header.
+fn check_header_read(read: usize) -> Result<(), Error> {
+    if read > 0 && read < HEADER_SIZE {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+// Read a message. Returns `None` if the peer closed the connection before a new frame.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>, Error> {
+    let mut header = [0; HEADER_SIZE];
+    let mut read = 0;
+    while read < HEADER_SIZE {
+        match reader.read(&mut header[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    check_header_read(read)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    let len = decode_header(&header)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+pub async fn write_message_async<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    msg: &T,
+) -> Result<(), Error> {
+    writer.write_all(&encode(msg)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// Read a message. Returns `None` if the peer closed the connection before a new frame.
+pub async fn read_message_async<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> Result<Option<T>, Error> {
+    let mut header = [0; HEADER_SIZE];
+    let mut read = 0;
+    while read < HEADER_SIZE {
+        match reader.read(&mut header[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    check_header_read(read)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    let len = decode_header(&header)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(exit_code: i32) -> ExitEvent {
+        ExitEvent {
+            container_id: "web".to_string(),
+            exec_id: Some("exec".to_string()),
+            pid: 42,
+            exit_code,
+            exited_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    fn header(version: u16, reserved: u16, len: u32) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&version.to_be_bytes());
+        header.extend_from_slice(&reserved.to_be_bytes());
+        header.extend_from_slice(&len.to_be_bytes());
+        header
+    }
+
+    async fn read(frame: &[u8]) -> Result<Option<ExitEvent>, Error> {
+        let mut reader = frame;
+        read_message_async(&mut reader).await
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let write = async {
+            for exit_code in 0..3 {
+                write_message_async(&mut writer, &event(exit_code))
+                    .await
+                    .unwrap();
+            }
+            drop(writer);
+        };
+        let read = async {
+            let mut exit_codes = Vec::new();
+            while let Some(event) = read_message_async::<_, ExitEvent>(&mut reader)
+                .await
+                .unwrap()
+            {
+                assert_eq!(event.container_id, "web");
+                assert_eq!(event.exec_id.as_deref(), Some("exec"));
+                assert_eq!(event.exited_at, SystemTime::UNIX_EPOCH);
+                exit_codes.push(event.exit_code);
+            }
+            exit_codes
+        };
+        let ((), exit_codes) = tokio::join!(write, read);
+        assert_eq!(exit_codes, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn frame_layout() {
+        let frame = encode(&event(1)).unwrap();
+        let payload = serde_json::to_vec(&event(1)).unwrap();
+        assert_eq!(frame[..HEADER_SIZE], header(1, 0, payload.len() as u32));
+        assert_eq!(frame[HEADER_SIZE..], payload);
+        assert_eq!(read(&frame).await.unwrap().unwrap().exit_code, 1);
+        assert_eq!(decode::<ExitEvent>(&frame).unwrap().exit_code, 1);
+    }
+
+    #[tokio::test]
+    async fn reject_oversize_messages() {
+        let frame = header(PROTOCOL_VERSION, 0, MAX_MESSAGE_SIZE as u32 + 1);
+        assert!(matches!(
+            read(&frame).await,
+            Err(Error::MessageTooLarge(len)) if len == MAX_MESSAGE_SIZE + 1
+        ));
+        let frame = header(PROTOCOL_VERSION, 0, u32::MAX);
+        assert!(matches!(read(&frame).await, Err(Error::MessageTooLarge(_))));
+
+        let mut writer = Vec::new();
+        let message = "a".repeat(MAX_MESSAGE_SIZE);
+        assert!(matches!(
+            write_message_async(&mut writer, &message).await,
+            Err(Error::MessageTooLarge(_))
+        ));
+        assert!(writer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_bad_headers() {
+        let mut frame = encode(&event(0)).unwrap();
+        frame[1] = 2;
+        assert!(matches!(
+            read(&frame).await,
+            Err(Error::UnsupportedVersion(2))
+        ));
+        let mut frame = encode(&event(0)).unwrap();
+        frame[3] = 1;
+        assert!(matches!(read(&frame).await, Err(Error::InvalidHeader)));
+    }
+
+    #[tokio::test]
+    async fn detect_truncated_frames() {
+        // The connection closed between frames.
+        assert!(read(&[]).await.unwrap().is_none());
+
+        let frame = encode(&event(0)).unwrap();
+        for len in [1, HEADER_SIZE - 1, HEADER_SIZE, frame.len() - 1] {
+            match read(&frame[..len]).await {
+                Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof, "{}", len),
+                result => panic!("{}: {:?}", len, result.map(|_| ())),
+            }
+        }
+
+        let mut payload = header(PROTOCOL_VERSION, 0, 2);
+        payload.extend_from_slice(b"{]");
+        assert!(matches!(read(&payload).await, Err(Error::Serde(_))));
+    }
+
+    #[test]
+    fn read_frames_sync() {
+        let mut frames = encode(&event(3)).unwrap();
+        frames.extend(encode(&event(4)).unwrap());
+        let mut reader = &frames[..];
+        let first: ExitEvent = read_message(&mut reader).unwrap().unwrap();
+        let second: ExitEvent = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!((first.exit_code, second.exit_code), (3, 4));
+        assert!(read_message::<_, ExitEvent>(&mut reader).unwrap().is_none());
+        let mut reader = &frames[..3];
+        assert!(matches!(
+            read_message::<_, ExitEvent>(&mut reader),
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
 }
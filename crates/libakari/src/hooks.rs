@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// OCI lifecycle hooks.
+// The server runs the hooks in the runtime namespace on the host, and the agent runs the hooks
+// in the container namespace in the guest.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use oci_spec::runtime::{Hook, Spec};
+use serde::{Deserialize, Serialize};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Hook {0:?} has an invalid timeout: {1}")]
+    InvalidTimeout(PathBuf, i64),
+    #[error("Hook {0:?} timed out")]
+    Timeout(PathBuf),
+    #[error("Hook {0:?} failed: {1}")]
+    Failed(PathBuf, ExitStatus),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Lifecycle {
+    // Host, after the container is created. Deprecated in favor of `CreateRuntime`.
+    Prestart,
+    // Host, after the container is created.
+    CreateRuntime,
+    // Guest, when the container is created.
+    CreateContainer,
+    // Guest, before the user process is started.
+    StartContainer,
+    // Host, after the user process is started.
+    Poststart,
+    // Host, after the container is deleted.
+    Poststop,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Creating,
+    Created,
+    Running,
+    Stopped,
+}
+
+// State of the container passed to the hooks on stdin, as defined by the OCI runtime spec.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    pub oci_version: String,
+    pub id: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    pub bundle: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl State {
+    pub fn new(spec: &Spec, id: &str, status: Status, pid: Option<u32>, bundle: &Path) -> Self {
+        Self {
+            oci_version: spec.version().clone(),
+            id: id.to_string(),
+            status,
+            pid,
+            bundle: bundle.to_path_buf(),
+            annotations: spec.annotations().clone(),
+        }
+    }
+}
+
+fn hooks(spec: &Spec, lifecycle: Lifecycle) -> &[Hook] {
+    let Some(hooks) = spec.hooks() else {
+        return &[];
+    };
+    let hooks = match lifecycle {
+        Lifecycle::Prestart => hooks.prestart(),
+        Lifecycle::CreateRuntime => hooks.create_runtime(),
+        Lifecycle::CreateContainer => hooks.create_container(),
+        Lifecycle::StartContainer => hooks.start_container(),
+        Lifecycle::Poststart => hooks.poststart(),
+        Lifecycle::Poststop => hooks.poststop(),
+    };
+    hooks.as_deref().unwrap_or_default()
+}
+
+// Run the hooks of a lifecycle point in order, stopping at the first failure.
+pub fn run(spec: &Spec, lifecycle: Lifecycle, state: &State) -> Result<(), Error> {
+    let hooks = hooks(spec, lifecycle);
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let state = serde_json::to_vec(state)?;
+    for hook in hooks {
+        log::info!("Running {:?} hook {:?}", lifecycle, hook.path());
+        run_hook(hook, &state)?;
+    }
+    Ok(())
+}
+
+fn run_hook(hook: &Hook, state: &[u8]) -> Result<(), Error> {
+    let timeout = match hook.timeout() {
+        Some(timeout) if timeout <= 0 => {
+            return Err(Error::InvalidTimeout(hook.path().clone(), timeout))
+        }
+        timeout => timeout.map(|timeout| Duration::from_secs(timeout as u64)),
+    };
+
+    let mut cmd = Command::new(hook.path());
+    // The args include the binary name itself.
+    if let Some((arg0, args)) = hook.args().as_deref().and_then(|args| args.split_first()) {
+        cmd.arg0(arg0);
+        cmd.args(args);
+    }
+    cmd.env_clear();
+    for env in hook.env().iter().flatten() {
+        if let Some((key, value)) = env.split_once('=') {
+            cmd.env(key, value);
+        }
+    }
+    cmd.stdin(Stdio::piped());
+
+    let mut child = cmd.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Write the state on another thread so that a hook that does not read it is bounded by
+        // the timeout too. The write fails once the hook exits or is killed.
+        let path = hook.path().clone();
+        let state = state.to_vec();
+        std::thread::spawn(move || {
+            // The hook may exit without reading the state.
+            if let Err(e) = stdin.write_all(&state) {
+                log::debug!("Failed to write the state to hook {:?}: {}", path, e);
+            }
+        });
+    }
+    let status = match timeout {
+        Some(timeout) => wait_timeout(&mut child, timeout)
+            .ok_or_else(|| Error::Timeout(hook.path().clone()))??,
+        None => child.wait()?,
+    };
+    if !status.success() {
+        return Err(Error::Failed(hook.path().clone(), status));
+    }
+    Ok(())
+}
+
+// Wait for the child to exit, and kill it if it does not exit within the timeout.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Option<std::io::Result<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(Ok(status)),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -1,7 +1,11 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+pub mod annotations;
 pub mod container_rpc;
+pub mod hooks;
 pub mod path;
+pub mod ports;
+pub mod validate;
 pub mod vm_config;
 pub mod vm_rpc;
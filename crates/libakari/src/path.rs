@@ -32,3 +32,8 @@ pub fn aux_sock_path(root_path: &Path, path: Option<PathBuf>) -> PathBuf {
         default_aux_sock_path
     })
 }
+
+// Return the path to the socket on which the server manages the published ports.
+pub fn ports_sock_path(root_path: &Path) -> PathBuf {
+    root_path.join("ports.sock")
+}
@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Ports of the host published to the containers, like `docker run -p`.
+// The server listens on a TCP port of the host and tunnels each connection over vsock to the
+// agent, which connects it to a port on the localhost of the guest. The containers share the
+// network of the VM, so a port of a container is a port on the localhost of the VM.
+// The client manages the published ports with framed `PortCommand`s on the ports socket of the
+// server, in the same format as the messages between the host and the agent.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid port mapping, expected [hostIp:]hostPort:containerPort: {0}")]
+    InvalidPortMapping(String),
+}
+
+// A host port published to a container port over TCP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortMapping {
+    pub host_ip: IpAddr,
+    pub host_port: u16,
+    pub container_port: u16,
+}
+
+// Split an IPv6 address in brackets off a mapping, e.g. "[::1]:8080:80".
+fn split_ipv6(s: &str) -> Option<(&str, &str)> {
+    let (ip, rest) = s.strip_prefix('[')?.split_once(']')?;
+    Some((ip, rest.strip_prefix(':')?))
+}
+
+impl FromStr for PortMapping {
+    type Err = Error;
+
+    // Parse "[hostIp:]hostPort:containerPort". The host IP defaults to all the addresses.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidPortMapping(s.to_string());
+        let (host_ip, ports) = if let Some((ip, ports)) = split_ipv6(s) {
+            (Some(ip), ports)
+        } else if s.matches(':').count() == 2 {
+            let (ip, ports) = s.split_once(':').ok_or_else(invalid)?;
+            (Some(ip), ports)
+        } else {
+            (None, s)
+        };
+        let host_ip = match host_ip {
+            Some(ip) => ip.parse().map_err(|_| invalid())?,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let (host_port, container_port) = ports.split_once(':').ok_or_else(invalid)?;
+        let port = |port: &str| match port.parse() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(port) => Ok(port),
+        };
+        Ok(Self {
+            host_ip,
+            host_port: port(host_port)?,
+            container_port: port(container_port)?,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host_ip {
+            IpAddr::V4(ip) => write!(f, "{}:", ip)?,
+            IpAddr::V6(ip) => write!(f, "[{}]:", ip)?,
+        }
+        write!(f, "{}:{}", self.host_port, self.container_port)
+    }
+}
+
+// Command sent from the client to the server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PortCommand {
+    Add {
+        container_id: String,
+        mapping: PortMapping,
+    },
+    List {
+        container_id: String,
+    },
+    // Remove the mappings of a host port on any host IP.
+    Remove {
+        container_id: String,
+        host_port: u16,
+    },
+}
+
+// Response to a `PortCommand` with the mappings of the container after the command.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PortResponse {
+    Ok(Vec<PortMapping>),
+    Error(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn mapping(host_ip: IpAddr, host_port: u16, container_port: u16) -> PortMapping {
+        PortMapping {
+            host_ip,
+            host_port,
+            container_port,
+        }
+    }
+
+    #[test]
+    fn parse_and_display_ipv4() {
+        let parsed: PortMapping = "127.0.0.1:8443:443".parse().unwrap();
+        assert_eq!(parsed, mapping(Ipv4Addr::LOCALHOST.into(), 8443, 443));
+        assert_eq!(parsed.to_string(), "127.0.0.1:8443:443");
+    }
+
+    #[test]
+    fn parse_and_display_ipv6() {
+        let parsed: PortMapping = "[::1]:8080:80".parse().unwrap();
+        assert_eq!(parsed, mapping(Ipv6Addr::LOCALHOST.into(), 8080, 80));
+        assert_eq!(parsed.to_string(), "[::1]:8080:80");
+
+        let parsed: PortMapping = "[2001:db8::1]:53:5353".parse().unwrap();
+        assert_eq!(parsed.to_string(), "[2001:db8::1]:53:5353");
+    }
+
+    #[test]
+    fn parse_without_host_ip() {
+        let parsed: PortMapping = "8080:80".parse().unwrap();
+        assert_eq!(parsed, mapping(Ipv4Addr::UNSPECIFIED.into(), 8080, 80));
+        // The default host IP is written out.
+        assert_eq!(parsed.to_string(), "0.0.0.0:8080:80");
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in [
+            "0.0.0.0:1:65535",
+            "192.168.64.1:8080:80",
+            "[::]:8080:80",
+            "[fe80::1]:22:2222",
+        ] {
+            let parsed: PortMapping = s.parse().unwrap();
+            assert_eq!(parsed.to_string(), s);
+            assert_eq!(parsed.to_string().parse::<PortMapping>().unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_mappings() {
+        for s in [
+            "",
+            "80",
+            ":80",
+            "8080:",
+            "0:80",
+            "8080:0",
+            "65536:80",
+            "8080:80:80:80",
+            "localhost:8080:80",
+            "::1:8080:80",
+            "[::1]8080:80",
+            "[::1:8080:80",
+            "[127.0.0.1]:8080:80x",
+            "[]:8080:80",
+            "1.2.3.4:8080",
+            "-1:80",
+        ] {
+            assert!(
+                matches!(s.parse::<PortMapping>(), Err(Error::InvalidPortMapping(m)) if m == s),
+                "{:?}",
+                s
+            );
+        }
+    }
+}
@@ -0,0 +1,448 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Validation of OCI runtime specs against what Akari supports on the macOS guest.
+// The client, the server and the agent run it so that an invalid spec is rejected before it
+// reaches the guest, and the agent never panics on one.
+
+use std::{
+    fmt,
+    path::{Component, PathBuf},
+};
+
+use oci_spec::runtime::{Hook, Mount, Process, Spec};
+use serde::{Deserialize, Serialize};
+
+use crate::annotations::{dns_config, ports};
+
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ValidationError {
+    #[error("process is not specified")]
+    ProcessNotSpecified,
+    #[error("process.args is empty")]
+    EmptyArgs,
+    #[error("process.cwd is not an absolute path: {0:?}")]
+    RelativeCwd(PathBuf),
+    #[error("process.env entry is not in the form of key=value: {0:?}")]
+    InvalidEnv(String),
+    #[error("root is not specified")]
+    RootNotSpecified,
+    #[error("root.path is empty")]
+    EmptyRootPath,
+    #[error("linux is not supported on the macOS guest")]
+    LinuxNotSupported,
+    #[error("hook path is not an absolute path: {0:?}")]
+    RelativeHookPath(PathBuf),
+    #[error("hook {0:?} has a non-positive timeout: {1}")]
+    InvalidHookTimeout(PathBuf, i64),
+    #[error("mount destination is not an absolute path without \"..\": {0:?}")]
+    InvalidMountDestination(PathBuf),
+    #[error("mount {0:?} has an unsupported type: {1}")]
+    UnsupportedMountType(PathBuf, String),
+    #[error("mount {0:?} has no source")]
+    MountSourceNotSpecified(PathBuf),
+    #[error("mount {0:?} has an unsupported option: {1}")]
+    UnsupportedMountOption(PathBuf, String),
+    #[error("hostname is not a valid host name: {0:?}")]
+    InvalidHostname(String),
+    #[error("annotation {0} has an invalid value: {1:?}")]
+    InvalidAnnotation(String, String),
+}
+
+// All the problems found in a spec.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid spec: ")?;
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+fn result(errors: Vec<ValidationError>) -> Result<(), ValidationErrors> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationErrors(errors))
+    }
+}
+
+fn check_process(process: &Process, errors: &mut Vec<ValidationError>) {
+    if process.args().as_ref().is_none_or(|args| args.is_empty()) {
+        errors.push(ValidationError::EmptyArgs);
+    }
+    if !process.cwd().is_absolute() {
+        errors.push(ValidationError::RelativeCwd(process.cwd().clone()));
+    }
+    for env in process.env().iter().flatten() {
+        if !env.contains('=') {
+            errors.push(ValidationError::InvalidEnv(env.clone()));
+        }
+    }
+}
+
+fn check_hooks<'a>(hooks: impl Iterator<Item = &'a Hook>, errors: &mut Vec<ValidationError>) {
+    for hook in hooks {
+        if !hook.path().is_absolute() {
+            errors.push(ValidationError::RelativeHookPath(hook.path().clone()));
+        }
+        if let Some(timeout) = hook.timeout() {
+            if timeout <= 0 {
+                errors.push(ValidationError::InvalidHookTimeout(
+                    hook.path().clone(),
+                    timeout,
+                ));
+            }
+        }
+    }
+}
+
+// Return whether an ID can name the files of a container on the host and in the guest: a single
+// path component that is not hidden, so that it never escapes the directory it is joined to.
+pub fn valid_container_id(id: &str) -> bool {
+    !id.is_empty() && !id.starts_with('.') && !id.contains(['/', '\0'])
+}
+
+// Return whether a name is a valid host name (RFC 1123): dot-separated labels of up to 63
+// letters, digits and hyphens, which do not start or end with a hyphen.
+pub fn valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// Kinds of mounts supported in the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountKind {
+    // A directory of the guest, which is under a VM share if it comes from the host.
+    Bind,
+    // A memory-backed filesystem.
+    Tmpfs,
+}
+
+// Options accepted for all kinds of mounts.
+const MOUNT_OPTIONS: [&str; 7] = ["ro", "rw", "nosuid", "nodev", "noexec", "bind", "rbind"];
+
+// Return the kind of a mount, or None if it is not supported.
+// A mount is a bind mount if its type is "bind" or it has the "bind" or "rbind" option.
+pub fn mount_kind(mount: &Mount) -> Option<MountKind> {
+    let bind = mount
+        .options()
+        .iter()
+        .flatten()
+        .any(|option| option == "bind" || option == "rbind");
+    match mount.typ().as_deref() {
+        Some("tmpfs") if !bind => Some(MountKind::Tmpfs),
+        Some("bind") | None => Some(MountKind::Bind),
+        Some(_) if bind => Some(MountKind::Bind),
+        _ => None,
+    }
+}
+
+fn check_mount(mount: &Mount, errors: &mut Vec<ValidationError>) {
+    let destination = mount.destination();
+    let escapes = destination
+        .components()
+        .any(|component| component == Component::ParentDir);
+    if !destination.is_absolute() || escapes {
+        errors.push(ValidationError::InvalidMountDestination(
+            destination.clone(),
+        ));
+    }
+    let Some(kind) = mount_kind(mount) else {
+        let typ = mount.typ().clone().unwrap_or_default();
+        errors.push(ValidationError::UnsupportedMountType(
+            destination.clone(),
+            typ,
+        ));
+        return;
+    };
+    if kind == MountKind::Bind
+        && mount
+            .source()
+            .as_deref()
+            .is_none_or(|source| source.as_os_str().is_empty())
+    {
+        errors.push(ValidationError::MountSourceNotSpecified(
+            destination.clone(),
+        ));
+    }
+    for option in mount.options().iter().flatten() {
+        let supported = MOUNT_OPTIONS.contains(&option.as_str())
+            || (kind == MountKind::Tmpfs && option.starts_with("size="));
+        if !supported {
+            errors.push(ValidationError::UnsupportedMountOption(
+                destination.clone(),
+                option.clone(),
+            ));
+        }
+    }
+}
+
+pub fn validate_spec(spec: &Spec) -> Result<(), ValidationErrors> {
+    let mut errors = Vec::new();
+
+    match spec.process() {
+        Some(process) => check_process(process, &mut errors),
+        None => errors.push(ValidationError::ProcessNotSpecified),
+    }
+    match spec.root() {
+        Some(root) if root.path().as_os_str().is_empty() => {
+            errors.push(ValidationError::EmptyRootPath)
+        }
+        Some(_) => {}
+        None => errors.push(ValidationError::RootNotSpecified),
+    }
+    if spec.linux().is_some() {
+        errors.push(ValidationError::LinuxNotSupported);
+    }
+    if let Some(hooks) = spec.hooks() {
+        let hooks = [
+            hooks.prestart(),
+            hooks.create_runtime(),
+            hooks.create_container(),
+            hooks.start_container(),
+            hooks.poststart(),
+            hooks.poststop(),
+        ];
+        check_hooks(hooks.into_iter().flatten().flatten(), &mut errors);
+    }
+    for mount in spec.mounts().iter().flatten() {
+        check_mount(mount, &mut errors);
+    }
+    if let Some(hostname) = spec.hostname() {
+        if !valid_hostname(hostname) {
+            errors.push(ValidationError::InvalidHostname(hostname.clone()));
+        }
+    }
+    if let Some(annotations) = spec.annotations() {
+        if let Err(e) = dns_config(annotations) {
+            errors.push(e);
+        }
+        if let Err(e) = ports(annotations) {
+            errors.push(e);
+        }
+    }
+
+    result(errors)
+}
+
+// Validate the process of an exec request.
+pub fn validate_process(process: &Process) -> Result<(), ValidationErrors> {
+    let mut errors = Vec::new();
+    check_process(process, &mut errors);
+    result(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{Linux, Root};
+
+    use super::*;
+
+    // Return the problems found in a spec, or none if it is valid.
+    fn spec_errors(spec: &Spec) -> Vec<ValidationError> {
+        validate_spec(spec).err().map(|e| e.0).unwrap_or_default()
+    }
+
+    fn process_errors(process: &Process) -> Vec<ValidationError> {
+        validate_process(process)
+            .err()
+            .map(|e| e.0)
+            .unwrap_or_default()
+    }
+
+    fn process(args: &[&str], cwd: &str, env: &[&str]) -> Process {
+        let mut process = Process::default();
+        process
+            .set_args(Some(args.iter().map(|arg| arg.to_string()).collect()))
+            .set_cwd(PathBuf::from(cwd))
+            .set_env(Some(env.iter().map(|env| env.to_string()).collect()));
+        process
+    }
+
+    fn root(path: &str) -> Root {
+        let mut root = Root::default();
+        root.set_path(PathBuf::from(path));
+        root
+    }
+
+    #[test]
+    fn validate_specs() {
+        let cases: [(&str, fn(&mut Spec), Vec<ValidationError>); 8] = [
+            ("valid", |_| {}, vec![]),
+            (
+                "missing process",
+                |spec| {
+                    spec.set_process(None);
+                },
+                vec![ValidationError::ProcessNotSpecified],
+            ),
+            (
+                "empty args",
+                |spec| {
+                    spec.set_process(Some(process(&[], "/", &[])));
+                },
+                vec![ValidationError::EmptyArgs],
+            ),
+            (
+                "env without =",
+                |spec| {
+                    spec.set_process(Some(process(&["sh"], "/", &["PATH=/bin", "TERM"])));
+                },
+                vec![ValidationError::InvalidEnv("TERM".to_string())],
+            ),
+            (
+                "linux section",
+                |spec| {
+                    spec.set_linux(Some(Linux::default()));
+                },
+                vec![ValidationError::LinuxNotSupported],
+            ),
+            (
+                "missing root",
+                |spec| {
+                    spec.set_root(None);
+                },
+                vec![ValidationError::RootNotSpecified],
+            ),
+            (
+                "empty root path",
+                |spec| {
+                    spec.set_root(Some(root("")));
+                },
+                vec![ValidationError::EmptyRootPath],
+            ),
+            (
+                "several problems",
+                |spec| {
+                    spec.set_process(Some(process(&[], "/", &["TERM"])))
+                        .set_root(None)
+                        .set_linux(Some(Linux::default()));
+                },
+                vec![
+                    ValidationError::EmptyArgs,
+                    ValidationError::InvalidEnv("TERM".to_string()),
+                    ValidationError::RootNotSpecified,
+                    ValidationError::LinuxNotSupported,
+                ],
+            ),
+        ];
+        for (name, modify, expected) in cases {
+            // The default spec is valid but for its Linux section and mounts.
+            let mut spec = Spec::default();
+            spec.set_linux(None).set_mounts(None);
+            modify(&mut spec);
+            assert_eq!(spec_errors(&spec), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn validate_processes() {
+        let cases = [
+            ("valid", process(&["sh"], "/", &["PATH=/bin"]), vec![]),
+            (
+                "empty args",
+                process(&[], "/", &[]),
+                vec![ValidationError::EmptyArgs],
+            ),
+            (
+                "relative cwd",
+                process(&["sh"], "tmp", &[]),
+                vec![ValidationError::RelativeCwd(PathBuf::from("tmp"))],
+            ),
+            (
+                "env without =",
+                process(&["sh"], "/", &["TERM"]),
+                vec![ValidationError::InvalidEnv("TERM".to_string())],
+            ),
+            (
+                "several problems",
+                process(&[], "tmp", &["A", "B=b", "C"]),
+                vec![
+                    ValidationError::EmptyArgs,
+                    ValidationError::RelativeCwd(PathBuf::from("tmp")),
+                    ValidationError::InvalidEnv("A".to_string()),
+                    ValidationError::InvalidEnv("C".to_string()),
+                ],
+            ),
+        ];
+        for (name, process, expected) in cases {
+            assert_eq!(process_errors(&process), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn display_all_errors() {
+        let errors = ValidationErrors(vec![
+            ValidationError::ProcessNotSpecified,
+            ValidationError::LinuxNotSupported,
+        ]);
+        assert_eq!(
+            errors.to_string(),
+            "Invalid spec: process is not specified; linux is not supported on the macOS guest"
+        );
+    }
+
+    #[test]
+    fn accept_valid_hostnames() {
+        let longest_label = "a".repeat(63);
+        let longest_name = [
+            &"a".repeat(63)[..],
+            &"b".repeat(63),
+            &"c".repeat(63),
+            &"d".repeat(61),
+        ]
+        .join(".");
+        assert_eq!(longest_name.len(), 253);
+        for name in [
+            "localhost",
+            "web-1",
+            "1web",
+            "db.example.com",
+            "A.Example",
+            &longest_label,
+            &longest_name,
+        ] {
+            assert!(valid_hostname(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_hostnames() {
+        let long_label = "a".repeat(64);
+        let long_name = format!("{}.a", [&"a".repeat(63)[..]; 4].join("."));
+        for name in [
+            "",
+            ".",
+            "web.",
+            ".web",
+            "db..example",
+            "-web",
+            "web-",
+            "web.-db",
+            "under_score",
+            "sp ace",
+            "web:80",
+            "caf\u{e9}",
+            &long_label,
+            &long_name,
+        ] {
+            assert!(!valid_hostname(name), "{:?}", name);
+        }
+    }
+}
@@ -33,6 +33,25 @@ pub struct MacosVmSharedDirectory {
     pub read_only: bool,
 }
 
+// Mount point of the shared directory with the automount tag in a macOS guest.
+pub const GUEST_AUTOMOUNT_PATH: &str = "/Volumes/My Shared Files";
+
+impl MacosVmSharedDirectory {
+    // Translate a host path under the shared directory to the path in the guest.
+    pub fn guest_path(&self, host_path: &Path) -> Option<PathBuf> {
+        if !self.automount {
+            return None;
+        }
+        let relative = host_path.strip_prefix(&self.path).ok()?;
+        Some(Path::new(GUEST_AUTOMOUNT_PATH).join(relative))
+    }
+}
+
+// Return the shared directory that is automounted in the guest.
+pub fn automount_share(shares: &[MacosVmSharedDirectory]) -> Option<&MacosVmSharedDirectory> {
+    shares.iter().find(|share| share.automount)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MacosVmDisplay {
@@ -64,9 +83,69 @@ pub enum Error {
     IoError(#[from] std::io::Error),
     #[error(transparent)]
     DeserializeError(#[from] serde_json::Error),
+    #[error("Only one shared directory is supported, but {0} are configured in shares")]
+    TooManyShares(usize),
 }
 
 pub fn load_vm_config(path: &Path) -> Result<MacosVmConfig, Error> {
     let json_string = std::fs::read_to_string(path)?;
-    Ok(serde_json::from_str(&json_string)?)
+    let config: MacosVmConfig = serde_json::from_str(&json_string)?;
+    // All the shares are attached with the automount tag, so the guest would only see one of
+    // them.
+    let shares = config.shares.as_ref().map_or(0, Vec::len);
+    if shares > 1 {
+        return Err(Error::TooManyShares(shares));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share(automount: bool) -> MacosVmSharedDirectory {
+        MacosVmSharedDirectory {
+            path: PathBuf::from("/Users/akari/share"),
+            automount,
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn translate_paths_in_share() {
+        let share = share(true);
+        assert_eq!(
+            share.guest_path(Path::new("/Users/akari/share")),
+            Some(PathBuf::from(GUEST_AUTOMOUNT_PATH))
+        );
+        assert_eq!(
+            share.guest_path(Path::new("/Users/akari/share/.akari/web/rootfs")),
+            Some(Path::new(GUEST_AUTOMOUNT_PATH).join(".akari/web/rootfs"))
+        );
+    }
+
+    #[test]
+    fn reject_paths_outside_share() {
+        let share = share(true);
+        for path in [
+            "/Users/akari",
+            "/Users/akari/shared",
+            "/tmp/share",
+            "share/rootfs",
+        ] {
+            assert_eq!(share.guest_path(Path::new(path)), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn reject_paths_without_automount() {
+        let hidden = share(false);
+        assert_eq!(
+            hidden.guest_path(Path::new("/Users/akari/share/rootfs")),
+            None
+        );
+        assert!(automount_share(&[hidden.clone()]).is_none());
+        let shares = [hidden, share(true)];
+        assert!(automount_share(&shares).is_some_and(|share| share.automount));
+    }
 }
@@ -1,9 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
-use std::path::PathBuf;
+use std::{io, os::unix::net::UnixStream, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 
 // Command to control the VM.
 pub enum VmCommand {
@@ -12,6 +13,8 @@ pub enum VmCommand {
     Pause,
     Resume,
     Connect(u32, PathBuf),
+    // Open a new connection to a vsock port of the guest and send it back.
+    ConnectStream(u32, oneshot::Sender<io::Result<UnixStream>>),
     Disconnect(u32),
     VsockSend(u32, Vec<u8>),
     VsockRecv(u32),
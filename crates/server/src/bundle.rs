@@ -0,0 +1,322 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// The agent cannot see the host filesystem, so the bundle of each container is staged in the
+// shared directory of the VM, and the spec is rewritten to the guest paths. The bundles are
+// staged in `<share>/.akari/<id>`, which belongs to Akari, so that removing a staged bundle never
+// touches the files of the user in the shared directory.
+// The process is chrooted into the rootfs by the agent, so process.cwd is kept as it is.
+
+use std::path::{Path, PathBuf};
+
+use libakari::{
+    validate::{mount_kind, MountKind},
+    vm_config::MacosVmSharedDirectory,
+};
+use oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+
+// Directory in the shared directory where the bundles are staged.
+const STAGING_DIR: &str = ".akari";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No writable shared directory with automount is configured")]
+    NoSharedDirectory,
+    #[error("Root path is not specified")]
+    RootfsPathIsNotSpecified,
+    #[error("Mount source {0:?} is not under the shared directory")]
+    MountSourceNotShared(PathBuf),
+    #[error("Bundle {0:?} is in the staging directory")]
+    BundleInStagingDirectory(PathBuf),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Spec(#[from] oci_spec::OciSpecError),
+}
+
+// A bundle staged in the shared directory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StagedBundle {
+    // Path on the host, removed when the container is deleted.
+    pub host_path: PathBuf,
+    // Path in the guest, sent to the agent as the bundle.
+    pub guest_path: PathBuf,
+}
+
+impl StagedBundle {
+    pub fn remove(&self) -> std::io::Result<()> {
+        if self.host_path.exists() {
+            std::fs::remove_dir_all(&self.host_path)?;
+        }
+        Ok(())
+    }
+}
+
+// Stage the bundle of a container in the shared directory.
+// The rootfs is used in place if it is already shared, and copied otherwise.
+pub fn stage(
+    share: &MacosVmSharedDirectory,
+    id: &str,
+    bundle: &Path,
+    spec: &Spec,
+) -> Result<StagedBundle, Error> {
+    if share.read_only {
+        return Err(Error::NoSharedDirectory);
+    }
+    let staging_path = share.path.join(STAGING_DIR);
+    let bundle = bundle.canonicalize()?;
+    if bundle.starts_with(&staging_path) {
+        return Err(Error::BundleInStagingDirectory(bundle));
+    }
+    let host_path = staging_path.join(id);
+    let guest_path = share
+        .guest_path(&host_path)
+        .ok_or(Error::NoSharedDirectory)?;
+    let staged = StagedBundle {
+        host_path,
+        guest_path,
+    };
+
+    // Remove a stale bundle of a previous run.
+    staged.remove()?;
+    std::fs::create_dir_all(&staged.host_path)?;
+    if let Err(e) = write_spec(share, &staged, &bundle, spec) {
+        let _ = staged.remove();
+        return Err(e);
+    }
+    Ok(staged)
+}
+
+fn write_spec(
+    share: &MacosVmSharedDirectory,
+    staged: &StagedBundle,
+    bundle: &Path,
+    spec: &Spec,
+) -> Result<(), Error> {
+    let root = spec
+        .root()
+        .as_ref()
+        .ok_or(Error::RootfsPathIsNotSpecified)?;
+    let rootfs = bundle.join(root.path()).canonicalize()?;
+    let guest_rootfs = match share.guest_path(&rootfs) {
+        Some(guest_rootfs) => guest_rootfs,
+        None => {
+            copy_dir(&rootfs, &staged.host_path.join("rootfs"))?;
+            staged.guest_path.join("rootfs")
+        }
+    };
+
+    let mut spec = spec.clone();
+    if let Some(root) = spec.root_mut() {
+        root.set_path(guest_rootfs);
+    }
+    // The guest can only see the host directories under the shared directory.
+    for mount in spec.mounts_mut().iter_mut().flatten() {
+        if mount_kind(mount) != Some(MountKind::Bind) {
+            continue;
+        }
+        let Some(source) = mount.source() else {
+            continue;
+        };
+        let source = bundle.join(source).canonicalize()?;
+        let guest_source = share
+            .guest_path(&source)
+            .ok_or(Error::MountSourceNotShared(source))?;
+        mount.set_source(Some(guest_source));
+    }
+    spec.save(staged.host_path.join("config.json"))?;
+    Ok(())
+}
+
+// Copy a directory tree, keeping the symbolic links as they are.
+fn copy_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    std::fs::set_permissions(dst, std::fs::metadata(src)?.permissions())?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let dst = dst.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &dst)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &dst)?;
+        } else if file_type.is_file() {
+            std::fs::copy(entry.path(), &dst)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use libakari::vm_config::GUEST_AUTOMOUNT_PATH;
+    use oci_spec::runtime::{Mount, Root};
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn share(dir: &TempDir) -> MacosVmSharedDirectory {
+        MacosVmSharedDirectory {
+            // The temporary directory can be behind a symbolic link, e.g. /var on macOS.
+            path: dir.0.canonicalize().unwrap(),
+            automount: true,
+            read_only: false,
+        }
+    }
+
+    // Write a rootfs with an executable and a symbolic link to it.
+    fn write_rootfs(rootfs: &Path) {
+        fs::create_dir_all(rootfs.join("bin")).unwrap();
+        fs::write(rootfs.join("bin/sh"), "#!").unwrap();
+        fs::set_permissions(rootfs.join("bin/sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("bin/sh", rootfs.join("sh")).unwrap();
+    }
+
+    fn spec(root_path: &Path, mounts: Vec<Mount>) -> Spec {
+        let mut root = Root::default();
+        root.set_path(root_path.to_path_buf());
+        let mut spec = Spec::default();
+        spec.set_root(Some(root)).set_mounts(Some(mounts));
+        spec
+    }
+
+    fn mount(destination: &str, typ: &str, source: Option<&Path>) -> Mount {
+        let mut mount = Mount::default();
+        mount
+            .set_destination(PathBuf::from(destination))
+            .set_typ(Some(typ.to_string()))
+            .set_source(source.map(Path::to_path_buf))
+            .set_options(None);
+        mount
+    }
+
+    fn staged_spec(staged: &StagedBundle) -> Spec {
+        Spec::load(staged.host_path.join("config.json")).unwrap()
+    }
+
+    fn root_path(spec: &Spec) -> &Path {
+        spec.root().as_ref().unwrap().path()
+    }
+
+    #[test]
+    fn copy_rootfs_outside_share() {
+        let dir = TempDir::new("share");
+        let bundle = TempDir::new("bundle");
+        write_rootfs(&bundle.0.join("rootfs"));
+        let share = share(&dir);
+
+        let staged = stage(&share, "web", &bundle.0, &spec(Path::new("rootfs"), vec![])).unwrap();
+        assert_eq!(staged.host_path, share.path.join(".akari/web"));
+        assert_eq!(
+            staged.guest_path,
+            Path::new(GUEST_AUTOMOUNT_PATH).join(".akari/web")
+        );
+        assert_eq!(
+            root_path(&staged_spec(&staged)),
+            staged.guest_path.join("rootfs")
+        );
+
+        let rootfs = staged.host_path.join("rootfs");
+        assert_eq!(fs::read_to_string(rootfs.join("bin/sh")).unwrap(), "#!");
+        let mode = fs::metadata(rootfs.join("bin/sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert_eq!(
+            fs::read_link(rootfs.join("sh")).unwrap(),
+            PathBuf::from("bin/sh")
+        );
+
+        staged.remove().unwrap();
+        assert!(!staged.host_path.exists());
+        assert!(bundle.0.join("rootfs/bin/sh").exists());
+    }
+
+    #[test]
+    fn use_shared_rootfs_in_place() {
+        let dir = TempDir::new("share");
+        let share = share(&dir);
+        let bundle = share.path.join("bundles/web");
+        write_rootfs(&bundle.join("rootfs"));
+        let guest_rootfs = Path::new(GUEST_AUTOMOUNT_PATH).join("bundles/web/rootfs");
+
+        // A relative root.path is relative to the bundle.
+        for path in [PathBuf::from("rootfs"), bundle.join("rootfs")] {
+            let staged = stage(&share, "web", &bundle, &spec(&path, vec![])).unwrap();
+            assert_eq!(root_path(&staged_spec(&staged)), guest_rootfs);
+            assert!(!staged.host_path.join("rootfs").exists());
+        }
+    }
+
+    #[test]
+    fn rewrite_bind_mount_sources() {
+        let dir = TempDir::new("share");
+        let share = share(&dir);
+        let bundle = share.path.join("bundles/web");
+        write_rootfs(&bundle.join("rootfs"));
+        fs::create_dir_all(bundle.join("data")).unwrap();
+        let mounts = vec![
+            mount("/data", "bind", Some(Path::new("data"))),
+            mount("/tmp", "tmpfs", Some(Path::new("tmpfs"))),
+        ];
+
+        let staged = stage(&share, "web", &bundle, &spec(Path::new("rootfs"), mounts)).unwrap();
+        let spec = staged_spec(&staged);
+        let sources: Vec<_> = spec
+            .mounts()
+            .iter()
+            .flatten()
+            .map(|mount| mount.source().clone())
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                Some(Path::new(GUEST_AUTOMOUNT_PATH).join("bundles/web/data")),
+                Some(PathBuf::from("tmpfs")),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_mount_sources_outside_share() {
+        let dir = TempDir::new("share");
+        let outside = TempDir::new("outside");
+        let share = share(&dir);
+        let bundle = share.path.join("bundles/web");
+        write_rootfs(&bundle.join("rootfs"));
+        let mounts = vec![mount("/data", "bind", Some(&outside.0))];
+
+        let result = stage(&share, "web", &bundle, &spec(Path::new("rootfs"), mounts));
+        assert!(matches!(result, Err(Error::MountSourceNotShared(_))));
+        assert!(!share.path.join(".akari/web").exists());
+    }
+
+    #[test]
+    fn reject_unusable_shares_and_bundles() {
+        let dir = TempDir::new("share");
+        let bundle = TempDir::new("bundle");
+        write_rootfs(&bundle.0.join("rootfs"));
+        let spec = spec(Path::new("rootfs"), vec![]);
+
+        let mut read_only = share(&dir);
+        read_only.read_only = true;
+        let result = stage(&read_only, "web", &bundle.0, &spec);
+        assert!(matches!(result, Err(Error::NoSharedDirectory)));
+
+        let mut hidden = share(&dir);
+        hidden.automount = false;
+        let result = stage(&hidden, "web", &bundle.0, &spec);
+        assert!(matches!(result, Err(Error::NoSharedDirectory)));
+
+        // A staged bundle cannot be staged again.
+        let share = share(&dir);
+        let staged = stage(&share, "web", &bundle.0, &spec).unwrap();
+        let result = stage(&share, "db", &staged.host_path, &spec);
+        assert!(matches!(result, Err(Error::BundleInStagingDirectory(_))));
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    ffi::CStr,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{fs::FileTypeExt, net::UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+// Host side of the pseudo-terminal of a process created with an OCI console socket.
+// The master is handed over to the console socket, and the slave is bridged to the stdio of
+// the process, which runs on a pty in the guest.
+#[derive(Debug)]
+pub struct Console {
+    // Keeps the pty open until the container is deleted.
+    _slave: OwnedFd,
+    path: PathBuf,
+}
+
+impl Console {
+    pub fn open(console_socket: &Path) -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty succeeded, so both fds are open and owned by us.
+        let (master, slave) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+        // The guest pty does the line discipline, so pass the bytes through as they are.
+        make_raw(&slave)?;
+        let path = tty_name(&slave)?;
+
+        let stream = UnixStream::connect(console_socket)?;
+        send_fd(
+            &stream,
+            master.as_raw_fd(),
+            path.to_string_lossy().as_bytes(),
+        )?;
+
+        Ok(Self {
+            _slave: slave,
+            path,
+        })
+    }
+}
+
+// Replace an OCI console socket passed as the stdio of a terminal process with a host pty.
+// The stdin and stdout paths point to the slave afterwards.
+pub fn replace_console_socket(
+    terminal: bool,
+    stdin: &mut String,
+    stdout: &mut String,
+) -> io::Result<Option<Console>> {
+    if !terminal || !is_socket(stdout) {
+        return Ok(None);
+    }
+    let console = Console::open(Path::new(stdout))?;
+    let path = console.path.to_string_lossy().to_string();
+    stdin.clone_from(&path);
+    *stdout = path;
+    Ok(Some(console))
+}
+
+fn is_socket(path: &str) -> bool {
+    !path.is_empty()
+        && std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+fn make_raw(fd: &OwnedFd) -> io::Result<()> {
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd.as_raw_fd(), &mut termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { libc::cfmakeraw(&mut termios) };
+    if unsafe { libc::tcsetattr(fd.as_raw_fd(), libc::TCSANOW, &termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn tty_name(fd: &OwnedFd) -> io::Result<PathBuf> {
+    let mut buf = [0 as libc::c_char; 128];
+    let ret = unsafe { libc::ttyname_r(fd.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Ok(PathBuf::from(name.to_string_lossy().to_string()))
+}
+
+// Send a file descriptor with SCM_RIGHTS along with `data`, as runc does for console sockets.
+fn send_fd(stream: &UnixStream, fd: RawFd, data: &[u8]) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let fd_size = std::mem::size_of::<RawFd>() as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fd_size) } as usize];
+
+    let mut msg = unsafe { std::mem::zeroed::<libc::msghdr>() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    // SAFETY: The control buffer is large enough for a single fd.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fd_size) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{path::Path, time::Duration};
+
+use anyhow::Result;
+use libakari::{
+    container_rpc::{self, ExitEvent, AGENT_EVENTS_VSOCK_PORT},
+    vm_rpc::VmCommand,
+};
+use log::{error, info, warn};
+use tokio::{
+    net::UnixStream,
+    sync::{broadcast, mpsc, watch},
+};
+
+use crate::{remove_stale_socket, sockets::SocketDir, stdio::connect_vsock};
+
+// Delay between the attempts to reconnect to the agent.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+async fn open(cmd_tx: &mpsc::Sender<VmCommand>, vsock_path: &Path) -> Result<UnixStream> {
+    remove_stale_socket(vsock_path)?;
+    cmd_tx
+        .send(VmCommand::Connect(
+            AGENT_EVENTS_VSOCK_PORT,
+            vsock_path.to_path_buf(),
+        ))
+        .await?;
+    connect_vsock(vsock_path).await
+}
+
+// Connect to the events port of the agent and broadcast the exit events it pushes. The
+// connection is reopened when it is lost. The returned receiver tells whether the events are
+// connected, so that waiters can check the states again for the exits pushed while they were
+// not. It is closed when the VM is gone.
+pub async fn connect(
+    cmd_tx: &mpsc::Sender<VmCommand>,
+    sockets: &SocketDir,
+    exits: broadcast::Sender<ExitEvent>,
+) -> Result<watch::Receiver<bool>> {
+    let vsock_path = sockets.server_socket("events")?;
+    let mut stream = open(cmd_tx, &vsock_path).await?;
+    let (connected_tx, connected_rx) = watch::channel(true);
+    let cmd_tx = cmd_tx.clone();
+    tokio::spawn(async move {
+        loop {
+            match container_rpc::read_message_async::<_, ExitEvent>(&mut stream).await {
+                Ok(Some(event)) => {
+                    info!(
+                        "Process {:?} of container {} exited with {}",
+                        event.exec_id, event.container_id, event.exit_code
+                    );
+                    // There may be no waiters.
+                    let _ = exits.send(event);
+                    continue;
+                }
+                Ok(None) => warn!("Events connection closed"),
+                Err(e) => error!("Failed to read an exit event: {}", e),
+            }
+            connected_tx.send_replace(false);
+            stream = loop {
+                if cmd_tx.is_closed() {
+                    return;
+                }
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+                match open(&cmd_tx, &vsock_path).await {
+                    Ok(stream) => break stream,
+                    Err(e) => warn!("Failed to reconnect events: {}", e),
+                }
+            };
+            info!("Events reconnected");
+            connected_tx.send_replace(true);
+        }
+    });
+    Ok(connected_rx)
+}
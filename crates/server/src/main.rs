@@ -8,47 +8,87 @@
 //! 2. Listen on a Unix domain socket (`aux.sock`) that accepts ttrpc containerd shim v2 requests.
 //! 3. Forward the requests to the agent via the vsock, with some exceptions:
 //!   - When creating a container, the server does the following:
-//!     - Create a symbolic link of the container rootfs in the shared directory.
-//!     - Modify the `config.json` file to use the shared directory.
+//!     - Stage the bundle in the shared directory, copying the rootfs unless it is already shared.
+//!     - Write a `config.json` file with the guest paths to the staged bundle.
 //!     - Send a request to the agent.
 //!     - Wait for the agent to finish creating the container.
 //!         - The agent creates a listener socket for the container when it finishes creating the container.
-//!     - Connect to the listener socket and expose it as a Unix domain socket.
+//!     - Connect to the listener socket and expose it as a Unix domain socket in `<root>/<id>/`,
+//!       which only the user can access.
+//!     - Replace the stdio paths with vsock ports and forward the streams between them.
+//!     - For a terminal with an OCI console socket, send the master of a host pty to the socket
+//!       and forward the slave instead.
 //! 4. Forward the responses from the agent to the containerd shim v2 requests.
+//! 5. Receive the exit events pushed from the agent and answer the wait requests with them.
+//! 6. Publish host TCP ports to the containers from annotations or the client, tunneling each
+//!    connection over vsock to the agent. The client manages them on a ports socket.
+//! 7. Run the OCI hooks in the runtime namespace (prestart, createRuntime, poststart and
+//!    poststop) on the host. The agent runs the other hooks in the guest.
+//! 8. Persist the state of each container in `<root>/<id>/state.json`, and take over the
+//!    containers of a previous server on startup, reconciling their states with the agent.
+
+mod bundle;
+mod console;
+mod events;
+mod ports;
+mod sockets;
+mod state;
+mod stdio;
+#[cfg(test)]
+mod test_util;
+mod vsock;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     os::{
         fd::AsRawFd,
         unix::{fs::FileTypeExt, net::UnixStream},
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::Result;
 use async_trait::async_trait;
+use bundle::StagedBundle;
 use clap::Parser;
+use console::Console;
 use containerd_shim::{
     api::{
         ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest,
-        Empty, KillRequest, StartRequest, StartResponse, StateRequest, StateResponse,
+        Empty, ExecProcessRequest, KillRequest, PidsRequest, PidsResponse, ResizePtyRequest,
+        StartRequest, StartResponse, StateRequest, StateResponse, StatsRequest, StatsResponse,
+        Status, WaitRequest, WaitResponse,
     },
     Context, DeleteResponse, Task as ShimTask, TtrpcContext, TtrpcResult,
 };
 use containerd_shim_protos::shim_async::{create_task, TaskClient};
 use libakari::{
-    path::{aux_sock_path, root_path},
-    vm_config::{load_vm_config, MacosVmConfig, MacosVmSerial},
+    annotations,
+    container_rpc::{self, ContainerStatus, ExitEvent, AGENT_VSOCK_PORT, PROCESS_DETAILS_METADATA},
+    hooks::{self, Lifecycle},
+    path::{aux_sock_path, ports_sock_path, root_path},
+    ports::{PortCommand, PortMapping, PortResponse},
+    validate::{valid_container_id, validate_process, validate_spec},
+    vm_config::{
+        automount_share, load_vm_config, MacosVmConfig, MacosVmSerial, MacosVmSharedDirectory,
+    },
     vm_rpc::{self, VmCommand},
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use oci_spec::runtime::{Process, Spec};
+use ports::PublishedPort;
+use sockets::SocketDir;
+use state::StateStore;
 use tokio::{
+    net::UnixListener,
     runtime::Runtime,
-    sync::{mpsc, RwLock},
+    sync::{broadcast, broadcast::error::RecvError, mpsc, watch, Mutex, OnceCell, RwLock},
     task::JoinHandle,
 };
 use ttrpc::asynchronous::{Client, Server};
+use vsock::{PortAllocator, PortRange};
 
 #[derive(clap::Parser)]
 struct Opts {
@@ -61,21 +101,346 @@ struct Opts {
     /// Specify the path to the VM console socket
     #[clap(short, long)]
     console_sock: Option<PathBuf>,
+    /// Range of the vsock ports for the stdio streams, e.g. 1234-65535
+    #[clap(long, default_value_t = PortRange::DEFAULT)]
+    vsock_ports: PortRange,
 }
 
 #[derive(Debug)]
 struct ContainerState {
+    status: ContainerStatus,
+    pid: Option<u32>,
     bundle: PathBuf,
-    vsock_port: u32,
+    spec: Box<Spec>,
+    staged_bundle: StagedBundle,
     vsock_path: PathBuf,
+    // vsock ports used by the stdio streams of the init process, and of each exec process until
+    // it is deleted.
+    vsock_ports: Vec<u32>,
+    exec_vsock_ports: HashMap<String, Vec<u32>>,
+    // Host ptys of the processes created with a console socket.
+    consoles: Vec<Console>,
+    // Host ports published to the container.
+    ports: Vec<PublishedPort>,
+}
+
+impl ContainerState {
+    fn to_state(&self, id: &str) -> state::State {
+        state::State {
+            oci_version: state::OCI_VERSION.to_string(),
+            id: id.to_string(),
+            status: self.status,
+            pid: self.pid,
+            bundle: self.bundle.clone(),
+            annotations: self.spec.annotations().clone(),
+            staged_bundle: self.staged_bundle.clone(),
+            vsock_path: self.vsock_path.clone(),
+            vsock_ports: self.vsock_ports.clone(),
+            exec_vsock_ports: self.exec_vsock_ports.clone(),
+            ports: self.ports.iter().map(PublishedPort::mapping).collect(),
+        }
+    }
 }
 
 type ContainerStateMap = HashMap<String, ContainerState>;
 
+fn invalid_argument(message: String) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::INVALID_ARGUMENT, message))
+}
+
+fn container_not_found(id: &str) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(
+        ttrpc::Code::NOT_FOUND,
+        format!("Container not found: {}", id),
+    ))
+}
+
+// Connect to the agent on the socket of a container.
+fn agent_client(vsock_path: &Path) -> TtrpcResult<TaskClient> {
+    let path = vsock_path.to_str().ok_or_else(|| {
+        ttrpc::Error::Others(format!("Invalid socket path: {}", vsock_path.display()))
+    })?;
+    let client = Client::connect(path)
+        .map_err(|e| ttrpc::Error::Others(format!("Failed to connect to the agent: {}", e)))?;
+    Ok(TaskClient::new(client))
+}
+
+// Connect to the agent on a socket just requested from the VM thread, which binds it
+// asynchronously.
+async fn connect_agent(vsock_path: &Path) -> TtrpcResult<TaskClient> {
+    let mut retries = 0;
+    loop {
+        match agent_client(vsock_path) {
+            Err(_) if retries < stdio::CONNECT_RETRIES => {
+                retries += 1;
+                tokio::time::sleep(stdio::CONNECT_RETRY_INTERVAL).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Check that a host port is not published to any container yet.
+fn check_port(state_map: &ContainerStateMap, mapping: &PortMapping) -> Result<()> {
+    let published = state_map
+        .values()
+        .flat_map(|state| state.ports.iter())
+        .any(|port| ports::overlaps(&port.mapping(), mapping));
+    if published {
+        anyhow::bail!("Port {} is already published", mapping.host_port);
+    }
+    Ok(())
+}
+
+// Run the hooks on the host without blocking the other requests.
+fn run_hooks(
+    spec: &Spec,
+    lifecycles: &[Lifecycle],
+    state: &hooks::State,
+) -> Result<(), hooks::Error> {
+    tokio::task::block_in_place(|| {
+        lifecycles
+            .iter()
+            .try_for_each(|lifecycle| hooks::run(spec, *lifecycle, state))
+    })
+}
+
 #[derive(Clone)]
 struct ContainerService {
     state_map: Arc<RwLock<ContainerStateMap>>,
     cmd_tx: mpsc::Sender<VmCommand>,
+    // Shared directories of the VM, used to stage the bundles.
+    shares: Vec<MacosVmSharedDirectory>,
+    // Exit events pushed from the agent, connected when the first container is created, and
+    // whether they are connected.
+    events: Arc<OnceCell<watch::Receiver<bool>>>,
+    exits: broadcast::Sender<ExitEvent>,
+    store: StateStore,
+    // vsock ports of the stdio streams, released when their containers are deleted.
+    vsock_ports: Arc<Mutex<PortAllocator>>,
+    sockets: SocketDir,
+    // Containers whose host hooks are running without the state map. It is only changed with
+    // the state map locked, and a container being created is not in the map yet.
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+impl ContainerService {
+    fn save(&self, id: &str, state: &ContainerState) {
+        if let Err(e) = self.store.save(&state.to_state(id)) {
+            error!("Failed to save the state of container {}: {}", id, e);
+        }
+    }
+
+    // Record the exits of the containers in their states.
+    async fn track_exits(self) {
+        let mut exits = self.exits.subscribe();
+        loop {
+            match exits.recv().await {
+                Ok(event) if event.exec_id.is_none() => {
+                    let mut state_map = self.state_map.write().await;
+                    if let Some(state) = state_map.get_mut(&event.container_id) {
+                        state.status = ContainerStatus::Stopped;
+                        self.save(&event.container_id, state);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => warn!("Dropped {} exit events", n),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    // Take over the containers of a previous server. Each container is reconnected to the agent,
+    // its state is reconciled with the agent, and its ports are published again. The stdio
+    // streams and consoles are not restored, since their host ends belonged to the previous
+    // server.
+    async fn restore(&self) -> Result<()> {
+        let states = self.store.load_all()?;
+        if states.is_empty() {
+            return Ok(());
+        }
+        wait_for_agent(&self.cmd_tx).await?;
+        self.events
+            .get_or_try_init(|| events::connect(&self.cmd_tx, &self.sockets, self.exits.clone()))
+            .await?;
+
+        let mut state_map = self.state_map.write().await;
+        for state in states {
+            let id = state.id.clone();
+            match self.restore_container(state).await {
+                Ok(state) => {
+                    let mut vsock_ports = self.vsock_ports.lock().await;
+                    let ports = state.exec_vsock_ports.values().flatten();
+                    for port in state.vsock_ports.iter().chain(ports) {
+                        if let Err(e) = vsock_ports.claim(*port) {
+                            warn!("Failed to claim the vsock port of container {}: {}", id, e);
+                        }
+                    }
+                    drop(vsock_ports);
+                    info!("Restored container {} ({:?})", id, state.status);
+                    self.save(&id, &state);
+                    state_map.insert(id, state);
+                }
+                Err(e) => error!("Failed to restore container {}: {}", id, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn restore_container(&self, state: state::State) -> Result<ContainerState> {
+        // The hooks of the spec are kept as they are in the staged bundle.
+        let spec = Spec::load(state.staged_bundle.host_path.join("config.json"))?;
+
+        // The previous server may have placed the socket elsewhere.
+        let vsock_path = self.sockets.container_socket(&state.id, "task")?;
+        remove_stale_socket(&vsock_path)?;
+        self.cmd_tx
+            .send(VmCommand::Connect(AGENT_VSOCK_PORT, vsock_path.clone()))
+            .await?;
+        let client = connect_agent(&vsock_path).await?;
+        let req = StateRequest {
+            id: state.id.clone(),
+            ..Default::default()
+        };
+        let (status, pid) = match client.state(Context::default(), &req).await {
+            Ok(res) => {
+                // A status unknown to this server is taken as stopped.
+                let status = match res.status.enum_value() {
+                    Ok(Status::CREATED) => ContainerStatus::Created,
+                    Ok(Status::RUNNING) => ContainerStatus::Running,
+                    _ => ContainerStatus::Stopped,
+                };
+                (status, (res.pid != 0).then_some(res.pid))
+            }
+            // The agent does not know the container if the VM has been restarted.
+            Err(ttrpc::Error::RpcStatus(status)) if status.code() == ttrpc::Code::NOT_FOUND => {
+                warn!("Container {} is not found in the VM", state.id);
+                (ContainerStatus::Stopped, None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut published = Vec::new();
+        if status != ContainerStatus::Stopped {
+            for mapping in state.ports {
+                match ports::publish(&self.cmd_tx, &state.id, mapping).await {
+                    Ok(port) => published.push(port),
+                    Err(e) => error!("Failed to publish {} to {}: {}", mapping, state.id, e),
+                }
+            }
+        }
+
+        Ok(ContainerState {
+            status,
+            pid,
+            bundle: state.bundle,
+            spec: Box::new(spec),
+            staged_bundle: state.staged_bundle,
+            vsock_path,
+            vsock_ports: state.vsock_ports,
+            exec_vsock_ports: state.exec_vsock_ports,
+            consoles: Vec::new(),
+            ports: published,
+        })
+    }
+
+    // Fail if the hooks of a container are running, until they finish.
+    async fn check_idle(&self, id: &str) -> TtrpcResult<()> {
+        if self.busy.lock().await.contains(id) {
+            return Err(ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::FAILED_PRECONDITION,
+                format!("Hooks of container {} are running", id),
+            )));
+        }
+        Ok(())
+    }
+
+    // Delete a container created in the agent when the rest of its creation fails, and release
+    // what was taken for it on the host.
+    async fn rollback_create(
+        &self,
+        client: &TaskClient,
+        id: &str,
+        vsock_ports: &[u32],
+        staged_bundle: &StagedBundle,
+    ) {
+        let req = DeleteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        if let Err(e) = client.delete(Context::default(), &req).await {
+            error!("Failed to delete container {}: {}", id, e);
+        }
+        self.vsock_ports.lock().await.release(vsock_ports);
+        let _ = staged_bundle.remove();
+    }
+
+    // Publish the host ports in the annotations of a container being created.
+    async fn publish_annotated_ports(
+        &self,
+        state_map: &ContainerStateMap,
+        id: &str,
+        spec: &Spec,
+    ) -> Result<Vec<PublishedPort>> {
+        let mappings = match spec.annotations() {
+            Some(annotations) => annotations::ports(annotations)?,
+            None => Vec::new(),
+        };
+        for (i, mapping) in mappings.iter().enumerate() {
+            check_port(state_map, mapping)?;
+            if mappings[..i]
+                .iter()
+                .any(|other| ports::overlaps(other, mapping))
+            {
+                anyhow::bail!("Port {} is published more than once", mapping.host_port);
+            }
+        }
+        ports::publish_all(&self.cmd_tx, id, &mappings).await
+    }
+
+    // Handle a command from the client to manage the published ports of a container, and
+    // return the ports published after the command.
+    async fn handle_port_cmd(&self, cmd: PortCommand) -> Result<Vec<PortMapping>> {
+        let mut state_map = self.state_map.write().await;
+        let container_id = match &cmd {
+            PortCommand::Add { container_id, .. }
+            | PortCommand::List { container_id }
+            | PortCommand::Remove { container_id, .. } => container_id.clone(),
+        };
+        if !state_map.contains_key(&container_id) {
+            anyhow::bail!("Container not found: {}", container_id);
+        }
+        match cmd {
+            PortCommand::Add { mapping, .. } => {
+                check_port(&state_map, &mapping)?;
+                let port = ports::publish(&self.cmd_tx, &container_id, mapping).await?;
+                let state = state_map.get_mut(&container_id).unwrap();
+                state.ports.push(port);
+                self.save(&container_id, state);
+            }
+            PortCommand::List { .. } => {}
+            PortCommand::Remove { host_port, .. } => {
+                let state = state_map.get_mut(&container_id).unwrap();
+                let count = state.ports.len();
+                state
+                    .ports
+                    .retain(|port| port.mapping().host_port != host_port);
+                if state.ports.len() == count {
+                    anyhow::bail!("Port {} is not published", host_port);
+                }
+                self.save(&container_id, state);
+                info!(
+                    "Unpublished port {} of container {}",
+                    host_port, container_id
+                );
+            }
+        }
+        Ok(state_map[&container_id]
+            .ports
+            .iter()
+            .map(PublishedPort::mapping)
+            .collect())
+    }
 }
 
 // Forwards the requests from the client or containerd shim v2 to the unix domain socket connected to the agent.
@@ -87,8 +452,10 @@ impl ShimTask for ContainerService {
         req: ConnectRequest,
     ) -> TtrpcResult<ConnectResponse> {
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = agent_client(&state.vsock_path)?;
         let res = client.connect(Context::default(), &req).await?;
         Ok(res)
     }
@@ -96,45 +463,135 @@ impl ShimTask for ContainerService {
     async fn create(
         &self,
         _ctx: &TtrpcContext,
-        req: CreateTaskRequest,
+        mut req: CreateTaskRequest,
     ) -> TtrpcResult<CreateTaskResponse> {
+        // The ID names the files of the container on the host.
+        if !valid_container_id(req.id()) {
+            return Err(invalid_argument(format!(
+                "Invalid container ID: {:?}",
+                req.id()
+            )));
+        }
         let mut state_map = self.state_map.write().await;
 
-        if state_map.contains_key(req.id()) {
+        if state_map.contains_key(req.id()) || self.busy.lock().await.contains(req.id()) {
             return Err(ttrpc::Error::Others("Container already exists".to_string()));
         }
 
-        // TODO: Create a symbolic link of the container rootfs in the shared directory.
-        // TODO: Modify the `config.json` file to use the shared directory.
-
         let bundle = PathBuf::from(req.bundle());
+        let spec = Spec::load(bundle.join("config.json"))
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to load the spec: {}", e)))?;
+        validate_spec(&spec).map_err(|e| invalid_argument(e.to_string()))?;
 
-        // Create a unique vsock port for the container.
-        // Find the smallest used vsock port
-        const DEFAULT_MIN_PORT: u32 = 1234;
-        let mut vsock_port = DEFAULT_MIN_PORT - 1;
-        state_map.values().for_each(|state| {
-            vsock_port = std::cmp::max(vsock_port, state.vsock_port);
-        });
-        vsock_port += 1;
+        self.events
+            .get_or_try_init(|| events::connect(&self.cmd_tx, &self.sockets, self.exits.clone()))
+            .await
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to connect events: {}", e)))?;
 
-        // TODO: Use root_path
-        let vsock_path = PathBuf::from(format!("/tmp/akari_vsock_{}", vsock_port));
+        // Each container has its own connection to the agent.
+        let vsock_path = self
+            .sockets
+            .container_socket(req.id(), "task")
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to create the socket: {}", e)))?;
+        remove_stale_socket(&vsock_path).map_err(|e| ttrpc::Error::Others(e.to_string()))?;
 
         self.cmd_tx
-            .send(VmCommand::Connect(vsock_port, vsock_path.clone()))
+            .send(VmCommand::Connect(AGENT_VSOCK_PORT, vsock_path.clone()))
             .await
-            .unwrap();
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to connect to the agent: {}", e)))?;
+        let client = connect_agent(&vsock_path).await?;
+
+        let console =
+            console::replace_console_socket(req.terminal, &mut req.stdin, &mut req.stdout)
+                .map_err(|e| ttrpc::Error::Others(format!("Failed to open console: {}", e)))?;
+
+        let share = automount_share(&self.shares)
+            .ok_or_else(|| ttrpc::Error::Others(bundle::Error::NoSharedDirectory.to_string()))?;
+        let staged_bundle =
+            tokio::task::block_in_place(|| bundle::stage(share, req.id(), &bundle, &spec))
+                .map_err(|e| ttrpc::Error::Others(format!("Failed to stage the bundle: {}", e)))?;
+        req.bundle = staged_bundle.guest_path.to_string_lossy().to_string();
+
+        let bridges = match stdio::rewrite(
+            &mut req.stdin,
+            &mut req.stdout,
+            &mut req.stderr,
+            &mut *self.vsock_ports.lock().await,
+        ) {
+            Ok(bridges) => bridges,
+            Err(e) => {
+                let _ = staged_bundle.remove();
+                return Err(ttrpc::Error::Others(e.to_string()));
+            }
+        };
+        let vsock_ports: Vec<u32> = bridges.iter().map(|bridge| bridge.port()).collect();
+
+        // The hooks in the runtime namespace run before the agent runs the createContainer hooks
+        // in the container namespace, as the OCI runtime spec orders them. They run without the
+        // state map so that a slow hook does not stall the requests for the other containers.
+        let hook_state = hooks::State::new(&spec, req.id(), hooks::Status::Creating, None, &bundle);
+        self.busy.lock().await.insert(req.id().to_string());
+        drop(state_map);
+        let hooks = run_hooks(
+            &spec,
+            &[Lifecycle::Prestart, Lifecycle::CreateRuntime],
+            &hook_state,
+        );
+        let mut state_map = self.state_map.write().await;
+        self.busy.lock().await.remove(req.id());
 
-        let client =
-            TaskClient::new(Client::connect(vsock_path.clone().to_str().unwrap()).unwrap());
-        let res = client.create(Context::default(), &req).await?;
+        let created = match hooks {
+            Ok(()) => client.create(Context::default(), &req).await,
+            Err(e) => Err(ttrpc::Error::Others(format!("Failed to run hooks: {}", e))),
+        };
+        let res = match created {
+            Ok(res) => res,
+            Err(e) => {
+                self.vsock_ports.lock().await.release(&vsock_ports);
+                let _ = staged_bundle.remove();
+                return Err(e);
+            }
+        };
+
+        let published = match self
+            .publish_annotated_ports(&state_map, req.id(), &spec)
+            .await
+        {
+            Ok(published) => published,
+            Err(e) => {
+                self.rollback_create(&client, req.id(), &vsock_ports, &staged_bundle)
+                    .await;
+                return Err(ttrpc::Error::Others(format!(
+                    "Failed to publish ports: {}",
+                    e
+                )));
+            }
+        };
+
+        if let Err(e) = stdio::connect(&self.cmd_tx, &self.sockets, req.id(), bridges).await {
+            // The published ports are closed when they are dropped.
+            drop(published);
+            self.rollback_create(&client, req.id(), &vsock_ports, &staged_bundle)
+                .await;
+            return Err(ttrpc::Error::Others(format!(
+                "Failed to connect stdio: {}",
+                e
+            )));
+        }
 
         let state = ContainerState {
+            status: ContainerStatus::Created,
+            pid: (res.pid != 0).then_some(res.pid),
             bundle,
-            vsock_port,
+            spec: Box::new(spec),
+            staged_bundle,
             vsock_path,
+            vsock_ports,
+            exec_vsock_ports: HashMap::new(),
+            consoles: console.into_iter().collect(),
+            ports: published,
         };
+        self.save(req.id(), &state);
         state_map.insert(req.id().to_string(), state);
 
         Ok(res)
@@ -142,58 +599,263 @@ impl ShimTask for ContainerService {
 
     async fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
-        let res = client.delete(Context::default(), &req).await?;
-        match state.bundle.try_exists() {
-            Ok(exist) => {
-                if exist
-                    && state
-                        .bundle
-                        .symlink_metadata()
-                        .unwrap()
-                        .file_type()
-                        .is_symlink()
-                {
-                    std::fs::remove_dir_all(&state.bundle).unwrap(); // TODO
-                } else {
-                    return Err(ttrpc::Error::Others("Bundle does not exist".to_string()));
-                }
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        self.check_idle(req.id()).await?;
+        let client = agent_client(&state.vsock_path)?;
+        let res = match client.delete(Context::default(), &req).await {
+            Ok(res) => res,
+            // The container is gone with the VM of a previous server.
+            Err(ttrpc::Error::RpcStatus(status))
+                if status.code() == ttrpc::Code::NOT_FOUND
+                    && req.exec_id.is_empty()
+                    && state.status == ContainerStatus::Stopped =>
+            {
+                warn!("Container {} is not found in the VM", req.id());
+                DeleteResponse::default()
             }
-            Err(e) => {
-                return Err(ttrpc::Error::Others(format!(
-                    "Failed to check if the bundle exists: {}",
-                    e
-                )));
+            Err(e) => return Err(e),
+        };
+        if !req.exec_id.is_empty() {
+            if let Some(ports) = state.exec_vsock_ports.remove(req.exec_id()) {
+                self.vsock_ports.lock().await.release(&ports);
+                self.save(req.id(), state);
             }
+            return Ok(res);
+        }
+
+        let hook_state = hooks::State::new(
+            &state.spec,
+            req.id(),
+            hooks::Status::Stopped,
+            None,
+            &state.bundle,
+        );
+        let spec = state.spec.clone();
+        self.busy.lock().await.insert(req.id().to_string());
+        drop(state_map);
+        if let Err(e) = run_hooks(&spec, &[Lifecycle::Poststop], &hook_state) {
+            warn!("Failed to run poststop hooks of {}: {}", req.id(), e);
+        }
+        let mut state_map = self.state_map.write().await;
+        self.busy.lock().await.remove(req.id());
+        // Nothing else removes the container while it is busy.
+        let Some(state) = state_map.remove(req.id()) else {
+            return Ok(res);
+        };
+
+        // The container is already gone from the agent, so its state goes away regardless.
+        if let Err(e) = state.staged_bundle.remove() {
+            error!("Failed to remove the staged bundle of {}: {}", req.id(), e);
+        }
+        let mut vsock_ports = self.vsock_ports.lock().await;
+        vsock_ports.release(&state.vsock_ports);
+        for ports in state.exec_vsock_ports.values() {
+            vsock_ports.release(ports);
+        }
+        drop(vsock_ports);
+        if let Err(e) = self.sockets.remove_container(req.id()) {
+            warn!("Failed to remove the sockets of {}: {}", req.id(), e);
+        }
+        if let Err(e) = self.store.remove(req.id()) {
+            error!(
+                "Failed to remove the state of container {}: {}",
+                req.id(),
+                e
+            );
         }
-        state_map.remove(req.id());
+        Ok(res)
+    }
+
+    async fn exec(&self, _ctx: &TtrpcContext, mut req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        if let Some(spec) = req.spec.as_ref() {
+            let process: Process = serde_json::from_slice(&spec.value)
+                .map_err(|e| invalid_argument(format!("Invalid process spec: {}", e)))?;
+            validate_process(&process).map_err(|e| invalid_argument(e.to_string()))?;
+        }
+
+        let mut state_map = self.state_map.write().await;
+        // Look the container up before the console and the vsock ports are taken for it.
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let console =
+            console::replace_console_socket(req.terminal, &mut req.stdin, &mut req.stdout)
+                .map_err(|e| ttrpc::Error::Others(format!("Failed to open console: {}", e)))?;
+        let bridges = stdio::rewrite(
+            &mut req.stdin,
+            &mut req.stdout,
+            &mut req.stderr,
+            &mut *self.vsock_ports.lock().await,
+        )
+        .map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+        let client = agent_client(&state.vsock_path)?;
+        let res = match client.exec(Context::default(), &req).await {
+            Ok(res) => res,
+            Err(e) => {
+                let ports: Vec<u32> = bridges.iter().map(|bridge| bridge.port()).collect();
+                self.vsock_ports.lock().await.release(&ports);
+                return Err(e);
+            }
+        };
+        state.exec_vsock_ports.insert(
+            req.exec_id.clone(),
+            bridges.iter().map(|bridge| bridge.port()).collect(),
+        );
+        state.consoles.extend(console);
+        self.save(req.id(), state);
+        stdio::connect(&self.cmd_tx, &self.sockets, req.id(), bridges)
+            .await
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to connect stdio: {}", e)))?;
         Ok(res)
     }
 
     async fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = agent_client(&state.vsock_path)?;
         let res = client.kill(Context::default(), &req).await?;
         Ok(res)
     }
 
+    async fn pids(&self, ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        let state_map = self.state_map.read().await;
+        let state = state_map
+            .get(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = agent_client(&state.vsock_path)?;
+        // Only the akari client asks for the process details.
+        let mut agent_ctx = Context::default();
+        if ctx.metadata.contains_key(PROCESS_DETAILS_METADATA) {
+            agent_ctx.add(PROCESS_DETAILS_METADATA.to_string(), "true".to_string());
+        }
+        let res = client.pids(agent_ctx, &req).await?;
+        Ok(res)
+    }
+
+    async fn resize_pty(&self, _ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        let mut state_map = self.state_map.write().await;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = agent_client(&state.vsock_path)?;
+        let res = client.resize_pty(Context::default(), &req).await?;
+        Ok(res)
+    }
+
     async fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        self.check_idle(req.id()).await?;
+        let client = agent_client(&state.vsock_path)?;
         let res = client.start(Context::default(), &req).await?;
+        if req.exec_id.is_empty() {
+            state.status = ContainerStatus::Running;
+            state.pid = Some(res.pid);
+            self.save(req.id(), state);
+            let hook_state = hooks::State::new(
+                &state.spec,
+                req.id(),
+                hooks::Status::Running,
+                Some(res.pid),
+                &state.bundle,
+            );
+            let spec = state.spec.clone();
+            self.busy.lock().await.insert(req.id().to_string());
+            drop(state_map);
+            if let Err(e) = run_hooks(&spec, &[Lifecycle::Poststart], &hook_state) {
+                warn!("Failed to run poststart hooks of {}: {}", req.id(), e);
+            }
+            let _state_map = self.state_map.write().await;
+            self.busy.lock().await.remove(req.id());
+        }
         Ok(res)
     }
 
     async fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
-        let res = client.state(Context::default(), &req).await?;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = agent_client(&state.vsock_path)?;
+        let mut res = client.state(Context::default(), &req).await?;
+        res.bundle = state.bundle.to_string_lossy().to_string();
+        Ok(res)
+    }
+
+    async fn stats(&self, _ctx: &TtrpcContext, req: StatsRequest) -> TtrpcResult<StatsResponse> {
+        let state_map = self.state_map.read().await;
+        let state = state_map
+            .get(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = agent_client(&state.vsock_path)?;
+        let res = client.stats(Context::default(), &req).await?;
         Ok(res)
     }
+
+    async fn wait(&self, _ctx: &TtrpcContext, req: WaitRequest) -> TtrpcResult<WaitResponse> {
+        let mut exits = self.exits.subscribe();
+        // Do not hold the state map while waiting so that the other requests can go through.
+        let client = {
+            let state_map = self.state_map.read().await;
+            let state = state_map
+                .get(req.id())
+                .ok_or_else(|| container_not_found(req.id()))?;
+            agent_client(&state.vsock_path)?
+        };
+        let mut connected = self
+            .events
+            .get()
+            .cloned()
+            .ok_or_else(|| ttrpc::Error::Others("Exit events not connected".to_string()))?;
+        let state_req = StateRequest {
+            id: req.id.clone(),
+            exec_id: req.exec_id.clone(),
+            ..Default::default()
+        };
+        loop {
+            let state = client.state(Context::default(), &state_req).await?;
+            if state.status.enum_value() == Ok(Status::STOPPED) {
+                return Ok(WaitResponse {
+                    exit_status: state.exit_status,
+                    exited_at: state.exited_at,
+                    ..Default::default()
+                });
+            }
+            // Check the state again when the process exits, or when the events are reconnected
+            // since the exit may have been missed.
+            loop {
+                tokio::select! {
+                    event = exits.recv() => match event {
+                        Ok(event)
+                            if event.container_id == req.id
+                                && event.exec_id.as_deref().unwrap_or_default() == req.exec_id =>
+                        {
+                            break
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(_)) => break,
+                        Err(RecvError::Closed) => {
+                            return Err(ttrpc::Error::Others("Exit events closed".to_string()))
+                        }
+                    },
+                    changed = connected.changed() => {
+                        if changed.is_err() {
+                            return Err(ttrpc::Error::Others("Exit events closed".to_string()));
+                        }
+                        if *connected.borrow_and_update() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 async fn handle_cmd(vm: &mut vmm::vm::Vm, cmd_rx: &mut mpsc::Receiver<VmCommand>) -> Result<()> {
@@ -208,6 +870,13 @@ async fn handle_cmd(vm: &mut vmm::vm::Vm, cmd_rx: &mut mpsc::Receiver<VmCommand>
         vm_rpc::VmCommand::Pause => todo!("Pause"),
         vm_rpc::VmCommand::Resume => todo!("Resume"),
         vm_rpc::VmCommand::Connect(port, path) => vm.connect(port, &path)?,
+        vm_rpc::VmCommand::ConnectStream(port, reply) => {
+            let stream = vm
+                .connect_stream(port)
+                .map_err(|e| std::io::Error::other(e.to_string()));
+            // The requester may have gone.
+            let _ = reply.send(stream);
+        }
         _ => todo!(),
     }
     Ok(())
@@ -237,6 +906,83 @@ fn vm_thread(vm_config: MacosVmConfig, cmd_rx: &mut mpsc::Receiver<VmCommand>) -
     Ok(())
 }
 
+// Serve the commands from the client to manage the published ports. Each connection is handled
+// in its own task.
+async fn serve_ports(listener: UnixListener, service: ContainerService) {
+    const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Failed to accept a ports connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let service = service.clone();
+        tokio::spawn(async move {
+            loop {
+                let cmd = match container_rpc::read_message_async(&mut stream).await {
+                    Ok(Some(cmd)) => cmd,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Failed to read a ports command: {}", e);
+                        break;
+                    }
+                };
+                let res = match service.handle_port_cmd(cmd).await {
+                    Ok(mappings) => PortResponse::Ok(mappings),
+                    Err(e) => PortResponse::Error(e.to_string()),
+                };
+                if let Err(e) = container_rpc::write_message_async(&mut stream, &res).await {
+                    error!("Failed to write a ports response: {}", e);
+                    break;
+                }
+            }
+        });
+    }
+}
+
+// Wait for the agent to accept connections after the VM has started.
+async fn wait_for_agent(cmd_tx: &mpsc::Sender<VmCommand>) -> Result<()> {
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+    const TIMEOUT: Duration = Duration::from_secs(120);
+    let started = std::time::Instant::now();
+    loop {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        cmd_tx
+            .send(VmCommand::ConnectStream(AGENT_VSOCK_PORT, reply_tx))
+            .await?;
+        match reply_rx.await? {
+            Ok(_) => return Ok(()),
+            Err(e) if started.elapsed() >= TIMEOUT => {
+                anyhow::bail!("The agent did not start: {}", e)
+            }
+            Err(_) => tokio::time::sleep(RETRY_DELAY).await,
+        }
+    }
+}
+
+// Remove a socket left by a previous server.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match path.try_exists() {
+        Ok(exist) => {
+            if exist {
+                let metadata = std::fs::metadata(path)?;
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file(path)?;
+                } else {
+                    anyhow::bail!("{:?} exists and is not a socket", path);
+                }
+            }
+        }
+        Err(e) => {
+            anyhow::bail!("Failed to check if {:?} exists: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
 async fn create_vm(
     vm_config: MacosVmConfig,
 ) -> Result<(
@@ -259,29 +1005,21 @@ async fn main() -> Result<()> {
     let root_path = root_path(opts.root)?;
     let aux_sock_path = aux_sock_path(&root_path, opts.aux_sock);
 
-    match aux_sock_path.try_exists() {
-        Ok(exist) => {
-            if exist {
-                let metadata = std::fs::metadata(&aux_sock_path)?;
-                if metadata.file_type().is_socket() {
-                    std::fs::remove_file(&aux_sock_path)?;
-                } else {
-                    anyhow::bail!("The aux socket path exists and is not a socket");
-                }
-            }
-        }
-        Err(e) => {
-            anyhow::bail!("Failed to check if the aux socket path exists: {}", e);
-        }
-    }
+    let ports_sock_path = ports_sock_path(&root_path);
+    remove_stale_socket(&aux_sock_path)?;
+    remove_stale_socket(&ports_sock_path)?;
 
     let console_path = opts
         .console_sock
         .unwrap_or_else(|| root_path.join("console.sock"));
 
+    let sockets = SocketDir::new(&root_path);
+    sockets.clean()?;
+
     let vm_config_path = root_path.join("vm.json");
     let mut vm_config = load_vm_config(&vm_config_path)?;
     vm_config.serial = Some(MacosVmSerial { path: console_path });
+    let shares = vm_config.shares.clone().unwrap_or_default();
 
     info!("Creating VM from config file: {:?}", vm_config_path);
     let (thread, cmd_tx) = create_vm(vm_config).await?;
@@ -289,11 +1027,30 @@ async fn main() -> Result<()> {
     info!("Starting VM");
     cmd_tx.send(vm_rpc::VmCommand::Start).await?;
 
-    info!("Listening on: {:?}", aux_sock_path);
-    let v = Box::new(ContainerService {
+    const EXIT_EVENT_CAPACITY: usize = 64;
+    let (exits, _) = broadcast::channel(EXIT_EVENT_CAPACITY);
+    let service = ContainerService {
         state_map: Arc::new(RwLock::new(HashMap::new())),
         cmd_tx,
-    }) as Box<dyn ShimTask + Sync + Send>;
+        shares,
+        events: Arc::new(OnceCell::new()),
+        exits,
+        store: StateStore::new(&root_path),
+        vsock_ports: Arc::new(Mutex::new(PortAllocator::new(opts.vsock_ports))),
+        sockets,
+        busy: Arc::new(Mutex::new(HashSet::new())),
+    };
+    tokio::spawn(service.clone().track_exits());
+    if let Err(e) = service.restore().await {
+        error!("Failed to restore the containers: {}", e);
+    }
+
+    info!("Listening on: {:?}", ports_sock_path);
+    let ports_listener = UnixListener::bind(&ports_sock_path)?;
+    tokio::spawn(serve_ports(ports_listener, service.clone()));
+
+    info!("Listening on: {:?}", aux_sock_path);
+    let v = Box::new(service) as Box<dyn ShimTask + Sync + Send>;
     let vservice = create_task(v.into());
 
     let mut server = Server::new()
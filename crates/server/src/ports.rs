@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::time::Duration;
+
+use anyhow::Result;
+use libakari::{
+    container_rpc::{self, PortConnectRequest, PortConnectResponse, AGENT_PORTS_VSOCK_PORT},
+    ports::PortMapping,
+    vm_rpc::VmCommand,
+};
+use log::{error, info};
+use tokio::{
+    net::{TcpListener, TcpStream, UnixStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// A host port published to a container. The port is unpublished when this is dropped, while the
+// connections already tunneled are kept until they are closed.
+#[derive(Debug)]
+pub struct PublishedPort {
+    mapping: PortMapping,
+    listener: JoinHandle<()>,
+}
+
+impl PublishedPort {
+    pub fn mapping(&self) -> PortMapping {
+        self.mapping
+    }
+}
+
+impl Drop for PublishedPort {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+// Return whether two mappings listen on the same host port. A mapping on all the addresses
+// overlaps with any other on the same port.
+pub fn overlaps(a: &PortMapping, b: &PortMapping) -> bool {
+    a.host_port == b.host_port
+        && (a.host_ip == b.host_ip || a.host_ip.is_unspecified() || b.host_ip.is_unspecified())
+}
+
+// Listen on the host port of a mapping and tunnel each connection to the container port.
+pub async fn publish(
+    cmd_tx: &mpsc::Sender<VmCommand>,
+    container_id: &str,
+    mapping: PortMapping,
+) -> Result<PublishedPort> {
+    let listener = TcpListener::bind((mapping.host_ip, mapping.host_port)).await?;
+    info!("Published {} to container {}", mapping, container_id);
+    let cmd_tx = cmd_tx.clone();
+    let container_id = container_id.to_string();
+    let listener = tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Failed to accept a connection on {}: {}", mapping, e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let cmd_tx = cmd_tx.clone();
+            let container_id = container_id.clone();
+            tokio::spawn(async move {
+                let port = mapping.container_port;
+                if let Err(e) = tunnel(stream, &cmd_tx, &container_id, port).await {
+                    error!("Failed to tunnel {} to {}: {}", peer, mapping, e);
+                }
+            });
+        }
+    });
+    Ok(PublishedPort { mapping, listener })
+}
+
+// Publish all the mappings, or none of them if any fails.
+pub async fn publish_all(
+    cmd_tx: &mpsc::Sender<VmCommand>,
+    container_id: &str,
+    mappings: &[PortMapping],
+) -> Result<Vec<PublishedPort>> {
+    let mut published = Vec::new();
+    for mapping in mappings {
+        published.push(publish(cmd_tx, container_id, *mapping).await?);
+    }
+    Ok(published)
+}
+
+// Forward a connection over a new vsock connection to the agent, which connects it to the port
+// on the localhost of the guest.
+async fn tunnel(
+    mut stream: TcpStream,
+    cmd_tx: &mpsc::Sender<VmCommand>,
+    container_id: &str,
+    port: u16,
+) -> Result<()> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    cmd_tx
+        .send(VmCommand::ConnectStream(AGENT_PORTS_VSOCK_PORT, reply_tx))
+        .await?;
+    let vsock = reply_rx.await??;
+    vsock.set_nonblocking(true)?;
+    let mut vsock = UnixStream::from_std(vsock)?;
+
+    let req = PortConnectRequest {
+        container_id: container_id.to_string(),
+        port,
+    };
+    container_rpc::write_message_async(&mut vsock, &req).await?;
+    match container_rpc::read_message_async(&mut vsock).await? {
+        Some(PortConnectResponse::Connected) => {}
+        Some(PortConnectResponse::Error(e)) => anyhow::bail!(e),
+        None => anyhow::bail!("The agent closed the tunnel"),
+    }
+    tokio::io::copy_bidirectional(&mut stream, &mut vsock).await?;
+    Ok(())
+}
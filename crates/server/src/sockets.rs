@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Paths of the Unix sockets that the VM binds for the vsock connections to the agent.
+// The sockets of a container are placed in `<root_path>/<id>/`, which only the user can access,
+// and are removed with the container. A path must fit in sun_path, so if it is too long, the
+// socket is placed in a private directory under /tmp named after a hash of the root path instead,
+// e.g. `/tmp/akari-<hash of root_path>/<hash of id>/task.sock`.
+
+use std::{
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use libakari::validate::valid_container_id;
+
+// Size of sun_path on macOS, including the terminating NUL.
+const SUN_PATH_LEN: usize = 104;
+
+const FALLBACK_DIR: &str = "/tmp";
+
+// FNV-1a, which is stable across builds unlike the hasher of std.
+fn hash(s: &[u8]) -> String {
+    let hash = s.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+// Create a directory that only the user can access. An existing directory must be owned by the
+// user, and is made private.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        Err(_) => {}
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:?} is not a directory owned by the user", dir),
+        ));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+// Remove the sockets in a directory, leaving the other files.
+fn remove_sockets(dir: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_socket() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_dir_all(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SocketDir {
+    root_path: PathBuf,
+    fallback_path: PathBuf,
+}
+
+impl SocketDir {
+    pub fn new(root_path: &Path) -> Self {
+        let root_hash = hash(root_path.as_os_str().as_encoded_bytes());
+        Self {
+            root_path: root_path.to_path_buf(),
+            fallback_path: Path::new(FALLBACK_DIR).join(format!("akari-{}", root_hash)),
+        }
+    }
+
+    fn fallback_container_path(&self, id: &str) -> PathBuf {
+        self.fallback_path.join(hash(id.as_bytes()))
+    }
+
+    // Return the directory of a container in the root path. The ID must be a single path
+    // component, so that the directory never escapes the root path.
+    fn container_path(&self, id: &str) -> io::Result<PathBuf> {
+        if !valid_container_id(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid container ID: {:?}", id),
+            ));
+        }
+        Ok(self.root_path.join(id))
+    }
+
+    // Return the path of a socket of a container, creating its directory.
+    pub fn container_socket(&self, id: &str, name: &str) -> io::Result<PathBuf> {
+        let file_name = format!("{}.sock", name);
+        let dir = self.container_path(id)?;
+        if dir.join(&file_name).as_os_str().len() < SUN_PATH_LEN {
+            create_private_dir(&dir)?;
+            return Ok(dir.join(file_name));
+        }
+        let dir = self.fallback_container_path(id);
+        create_private_dir(&self.fallback_path)?;
+        create_private_dir(&dir)?;
+        Ok(dir.join(file_name))
+    }
+
+    // Return the path of a socket shared by the containers.
+    pub fn server_socket(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.root_path.join(format!("{}.sock", name));
+        if path.as_os_str().len() < SUN_PATH_LEN {
+            return Ok(path);
+        }
+        create_private_dir(&self.fallback_path)?;
+        Ok(self.fallback_path.join(format!("{}.sock", name)))
+    }
+
+    // Remove the sockets of a deleted container.
+    pub fn remove_container(&self, id: &str) -> io::Result<()> {
+        remove_sockets(&self.container_path(id)?)?;
+        remove_dir_all(&self.fallback_container_path(id))
+    }
+
+    // Remove the sockets left by a previous server. The sockets in the root path that clients
+    // connect to are left to the server.
+    pub fn clean(&self) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.root_path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                remove_sockets(&entry.path())?;
+            }
+        }
+        remove_dir_all(&self.fallback_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn place_sockets_in_root_path() {
+        let root = TempDir::new("root");
+        let sockets = SocketDir::new(&root.0);
+        let path = sockets.container_socket("web", "task").unwrap();
+        assert_eq!(path, root.0.join("web").join("task.sock"));
+        let mode = fs::metadata(root.0.join("web")).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert_eq!(
+            sockets.server_socket("events").unwrap(),
+            root.0.join("events.sock")
+        );
+    }
+
+    #[test]
+    fn fall_back_for_long_paths() {
+        let root = TempDir::new("long");
+        let sockets = SocketDir::new(&root.0.join("a".repeat(SUN_PATH_LEN)));
+        let _fallback = TempDir(sockets.fallback_path.clone());
+        let path = sockets.container_socket("web", "task").unwrap();
+        assert_eq!(
+            path,
+            sockets.fallback_path.join(hash(b"web")).join("task.sock")
+        );
+        assert!(path.as_os_str().len() < SUN_PATH_LEN);
+        sockets.remove_container("web").unwrap();
+        assert!(!sockets.fallback_path.join(hash(b"web")).exists());
+    }
+
+    #[test]
+    fn reject_invalid_ids() {
+        let root = TempDir::new("invalid");
+        let sockets = SocketDir::new(&root.0.join("root"));
+        for id in ["", ".", "..", "../web", "web/..", "a/b", "/tmp"] {
+            let e = sockets.container_socket(id, "task").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", id);
+            let e = sockets.remove_container(id).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", id);
+        }
+        assert!(!root.0.join("root").exists());
+    }
+}
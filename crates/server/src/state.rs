@@ -0,0 +1,238 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Persistent state of the containers, so that a restarted server can take them over.
+// The state of each container is saved to `<root_path>/<id>/state.json`. It is the OCI runtime
+// state extended with what the server needs to reach the container in the VM, and is written to
+// a temporary file and renamed, so that a crash never leaves a partially written state behind.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use libakari::{container_rpc::ContainerStatus, ports::PortMapping, validate::valid_container_id};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::bundle::StagedBundle;
+
+pub const OCI_VERSION: &str = "1.0.2";
+
+const STATE_FILE: &str = "state.json";
+const TEMP_FILE: &str = "state.json.tmp";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    pub oci_version: String,
+    pub id: String,
+    pub status: ContainerStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    pub bundle: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+    // The rest is specific to Akari.
+    pub staged_bundle: StagedBundle,
+    pub vsock_path: PathBuf,
+    pub vsock_ports: Vec<u32>,
+    #[serde(default)]
+    pub exec_vsock_ports: HashMap<String, Vec<u32>>,
+    pub ports: Vec<PortMapping>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    root_path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(root_path: &Path) -> Self {
+        Self {
+            root_path: root_path.to_path_buf(),
+        }
+    }
+
+    // Return the directory of a container, rejecting the IDs that would escape the root path.
+    fn dir(&self, id: &str) -> io::Result<PathBuf> {
+        if !valid_container_id(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid container ID: {:?}", id),
+            ));
+        }
+        Ok(self.root_path.join(id))
+    }
+
+    pub fn save(&self, state: &State) -> io::Result<()> {
+        let dir = self.dir(&state.id)?;
+        fs::create_dir_all(&dir)?;
+        let temp_path = dir.join(TEMP_FILE);
+        let mut file = File::create(&temp_path)?;
+        serde_json::to_writer_pretty(&mut file, state)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, dir.join(STATE_FILE))
+    }
+
+    // Remove the state of a container, and its directory unless something else is left in it.
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        let dir = self.dir(id)?;
+        if let Err(e) = fs::remove_file(dir.join(STATE_FILE)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let _ = fs::remove_dir(&dir);
+        Ok(())
+    }
+
+    // Load all the saved states. Unreadable states and states that do not belong to their
+    // directories are skipped, and the temporary files of interrupted writes are removed.
+    pub fn load_all(&self) -> io::Result<Vec<State>> {
+        let mut states = Vec::new();
+        for entry in fs::read_dir(&self.root_path)? {
+            let dir = entry?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            let temp_path = dir.join(TEMP_FILE);
+            if temp_path.exists() {
+                fs::remove_file(&temp_path)?;
+            }
+            let path = dir.join(STATE_FILE);
+            if !path.exists() {
+                continue;
+            }
+            let state = fs::read(&path)
+                .map_err(serde_json::Error::io)
+                .and_then(|data| serde_json::from_slice::<State>(&data));
+            match state {
+                Ok(state) if dir.file_name() == Some(state.id.as_ref()) => states.push(state),
+                Ok(state) => error!("State of {:?} found in {:?}", state.id, path),
+                Err(e) => error!("Failed to load the state in {:?}: {}", path, e),
+            }
+        }
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn state(id: &str) -> State {
+        State {
+            oci_version: OCI_VERSION.to_string(),
+            id: id.to_string(),
+            status: ContainerStatus::Running,
+            pid: Some(42),
+            bundle: PathBuf::from("/bundles").join(id),
+            annotations: None,
+            staged_bundle: StagedBundle {
+                host_path: PathBuf::from("/share/.akari").join(id),
+                guest_path: PathBuf::from("/mnt/share/.akari").join(id),
+            },
+            vsock_path: PathBuf::from("/run/akari").join(id).join("task.sock"),
+            vsock_ports: vec![1234, 1235],
+            exec_vsock_ports: HashMap::from([("exec".to_string(), vec![1236])]),
+            ports: vec!["127.0.0.1:8080:80".parse().unwrap()],
+        }
+    }
+
+    fn ids(store: &StateStore) -> Vec<String> {
+        let mut ids: Vec<String> = store
+            .load_all()
+            .unwrap()
+            .into_iter()
+            .map(|state| state.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn save_load_and_remove() {
+        let dir = TempDir::new("round-trip");
+        let store = StateStore::new(&dir.0);
+        store.save(&state("a")).unwrap();
+        store.save(&state("b")).unwrap();
+        let mut updated = state("b");
+        updated.status = ContainerStatus::Stopped;
+        store.save(&updated).unwrap();
+
+        let mut states = store.load_all().unwrap();
+        states.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(states.len(), 2);
+        assert_eq!(
+            serde_json::to_value(&states[0]).unwrap(),
+            serde_json::to_value(state("a")).unwrap()
+        );
+        assert_eq!(states[1].status, ContainerStatus::Stopped);
+
+        store.remove("a").unwrap();
+        assert!(!dir.0.join("a").exists());
+        assert_eq!(ids(&store), ["b"]);
+        // Removing a removed state succeeds.
+        store.remove("a").unwrap();
+    }
+
+    #[test]
+    fn keep_other_files_on_remove() {
+        let dir = TempDir::new("other-files");
+        let store = StateStore::new(&dir.0);
+        store.save(&state("a")).unwrap();
+        fs::write(dir.0.join("a").join("task.sock"), "").unwrap();
+        store.remove("a").unwrap();
+        assert!(dir.0.join("a").join("task.sock").exists());
+        assert!(!dir.0.join("a").join(STATE_FILE).exists());
+    }
+
+    #[test]
+    fn clean_up_interrupted_writes() {
+        let dir = TempDir::new("temp-files");
+        let store = StateStore::new(&dir.0);
+        store.save(&state("a")).unwrap();
+        assert!(!dir.0.join("a").join(TEMP_FILE).exists());
+        fs::write(dir.0.join("a").join(TEMP_FILE), "{").unwrap();
+        fs::create_dir(dir.0.join("b")).unwrap();
+        fs::write(dir.0.join("b").join(TEMP_FILE), "{").unwrap();
+
+        assert_eq!(ids(&store), ["a"]);
+        assert!(!dir.0.join("a").join(TEMP_FILE).exists());
+        assert!(!dir.0.join("b").join(TEMP_FILE).exists());
+    }
+
+    #[test]
+    fn skip_unreadable_and_misplaced_states() {
+        let dir = TempDir::new("skip");
+        let store = StateStore::new(&dir.0);
+        store.save(&state("a")).unwrap();
+        fs::create_dir(dir.0.join("broken")).unwrap();
+        fs::write(dir.0.join("broken").join(STATE_FILE), "{").unwrap();
+        fs::create_dir(dir.0.join("moved")).unwrap();
+        fs::copy(
+            dir.0.join("a").join(STATE_FILE),
+            dir.0.join("moved").join(STATE_FILE),
+        )
+        .unwrap();
+        fs::write(dir.0.join("events.sock"), "").unwrap();
+        assert_eq!(ids(&store), ["a"]);
+    }
+
+    #[test]
+    fn reject_invalid_ids() {
+        let dir = TempDir::new("invalid");
+        let store = StateStore::new(&dir.0.join("root"));
+        for id in ["", ".", "..", "../escaped", "a/b", ".hidden"] {
+            let e = store.save(&state(id)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", id);
+            let e = store.remove(id).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", id);
+        }
+        assert!(!dir.0.join("escaped").exists());
+    }
+}
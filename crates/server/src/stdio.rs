@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use libakari::{container_rpc::vsock_uri, vm_rpc::VmCommand};
+use log::error;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, net::UnixStream, sync::mpsc};
+
+use crate::{
+    remove_stale_socket,
+    sockets::SocketDir,
+    vsock::{self, PortAllocator},
+};
+
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    // From the host path to the process.
+    Input,
+    // From the process to the host path.
+    Output,
+}
+
+// A stdio stream of a process between a host path (FIFO, file or pty) and a vsock port.
+#[derive(Debug)]
+pub struct StdioBridge {
+    path: PathBuf,
+    port: u32,
+    direction: Direction,
+}
+
+impl StdioBridge {
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+}
+
+// Replace each non-empty stdio path with a vsock URI on a port from the allocator.
+pub fn rewrite(
+    stdin: &mut String,
+    stdout: &mut String,
+    stderr: &mut String,
+    vsock_ports: &mut PortAllocator,
+) -> Result<Vec<StdioBridge>, vsock::Error> {
+    let streams: Vec<_> = [
+        (stdin, Direction::Input),
+        (stdout, Direction::Output),
+        (stderr, Direction::Output),
+    ]
+    .into_iter()
+    .filter(|(path, _)| !path.is_empty())
+    .collect();
+    let ports = vsock_ports.allocate(streams.len())?;
+    let bridges = streams
+        .into_iter()
+        .zip(ports)
+        .map(|((path, direction), port)| StdioBridge {
+            path: PathBuf::from(std::mem::replace(path, vsock_uri(port))),
+            port,
+            direction,
+        })
+        .collect();
+    Ok(bridges)
+}
+
+// Connect to the vsock ports of the streams and forward them in the background.
+// This must be called after the agent has created the process and bound the ports.
+pub async fn connect(
+    cmd_tx: &mpsc::Sender<VmCommand>,
+    sockets: &SocketDir,
+    id: &str,
+    bridges: Vec<StdioBridge>,
+) -> Result<()> {
+    for bridge in bridges {
+        let vsock_path = sockets.container_socket(id, &format!("stdio-{}", bridge.port))?;
+        remove_stale_socket(&vsock_path)?;
+        cmd_tx
+            .send(VmCommand::Connect(bridge.port, vsock_path.clone()))
+            .await?;
+        tokio::spawn(async move {
+            if let Err(e) = forward(&bridge, &vsock_path).await {
+                error!("Failed to forward {:?}: {}", bridge.path, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// The VM thread binds the sockets asynchronously, so connecting to them is retried for a while.
+pub const CONNECT_RETRIES: usize = 50;
+pub const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+pub async fn connect_vsock(vsock_path: &Path) -> Result<UnixStream> {
+    let mut retries = 0;
+    loop {
+        match UnixStream::connect(vsock_path).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if retries >= CONNECT_RETRIES => return Err(e.into()),
+            Err(_) => {
+                retries += 1;
+                tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn forward(bridge: &StdioBridge, vsock_path: &Path) -> Result<()> {
+    let mut stream = connect_vsock(vsock_path).await?;
+    // The path can be the slave of a console pty, which must not become our controlling terminal.
+    match bridge.direction {
+        Direction::Input => {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOCTTY)
+                .open(&bridge.path)
+                .await?;
+            tokio::io::copy(&mut file, &mut stream).await?;
+            stream.shutdown().await?;
+        }
+        Direction::Output => {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .custom_flags(libc::O_NOCTTY)
+                .open(&bridge.path)
+                .await?;
+            tokio::io::copy(&mut stream, &mut file).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Allocation of the vsock ports that the agent binds for the stdio streams of the processes.
+// The ports are allocated next-fit from a configurable range, skipping the fixed ports of the
+// agent, so that a released port is reused only after the rest of the range has been used. This
+// keeps a late connection to a released port from reaching the stream of another process.
+
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use libakari::container_rpc::{AGENT_EVENTS_VSOCK_PORT, AGENT_PORTS_VSOCK_PORT, AGENT_VSOCK_PORT};
+
+// The ports the agent listens on.
+const RESERVED_PORTS: [u32; 3] = [
+    AGENT_VSOCK_PORT,
+    AGENT_EVENTS_VSOCK_PORT,
+    AGENT_PORTS_VSOCK_PORT,
+];
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Invalid vsock port range, expected first-last: {0}")]
+    InvalidRange(String),
+    #[error("No {0} free vsock ports left in {1}")]
+    Exhausted(usize, PortRange),
+    #[error("vsock port {0} is out of {1}")]
+    OutOfRange(u32, PortRange),
+    #[error("vsock port {0} is already in use")]
+    InUse(u32),
+}
+
+// An inclusive range of vsock ports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    first: u32,
+    last: u32,
+}
+
+impl PortRange {
+    pub const DEFAULT: Self = Self {
+        first: 1234,
+        last: 65535,
+    };
+
+    pub fn new(first: u32, last: u32) -> Result<Self, Error> {
+        // u32::MAX is VMADDR_PORT_ANY.
+        if first == 0 || first > last || last == u32::MAX {
+            return Err(Error::InvalidRange(format!("{}-{}", first, last)));
+        }
+        Ok(Self { first, last })
+    }
+
+    fn contains(&self, port: u32) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+
+    fn len(&self) -> u64 {
+        (self.last - self.first) as u64 + 1
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidRange(s.to_string());
+        let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+        let first = first.parse().map_err(|_| invalid())?;
+        let last = last.parse().map_err(|_| invalid())?;
+        Self::new(first, last).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+#[derive(Debug)]
+pub struct PortAllocator {
+    range: PortRange,
+    // Ports in the range that are never allocated.
+    reserved: BTreeSet<u32>,
+    used: BTreeSet<u32>,
+    // Where the next search starts.
+    next: u32,
+}
+
+impl PortAllocator {
+    pub fn new(range: PortRange) -> Self {
+        let reserved = RESERVED_PORTS
+            .into_iter()
+            .filter(|port| range.contains(*port))
+            .collect();
+        Self {
+            range,
+            reserved,
+            used: BTreeSet::new(),
+            next: range.first,
+        }
+    }
+
+    fn is_free(&self, port: u32) -> bool {
+        !self.reserved.contains(&port) && !self.used.contains(&port)
+    }
+
+    fn free_count(&self) -> u64 {
+        self.range.len() - (self.reserved.len() + self.used.len()) as u64
+    }
+
+    // Allocate `count` ports, or none of them if there are not enough.
+    pub fn allocate(&mut self, count: usize) -> Result<Vec<u32>, Error> {
+        if (count as u64) > self.free_count() {
+            return Err(Error::Exhausted(count, self.range));
+        }
+        let mut ports = Vec::with_capacity(count);
+        let mut port = self.next;
+        while ports.len() < count {
+            if self.is_free(port) {
+                ports.push(port);
+            }
+            port = if port == self.range.last {
+                self.range.first
+            } else {
+                port + 1
+            };
+        }
+        self.used.extend(&ports);
+        self.next = port;
+        Ok(ports)
+    }
+
+    // Mark a port allocated before, e.g. by a previous server, as used.
+    pub fn claim(&mut self, port: u32) -> Result<(), Error> {
+        if !self.range.contains(port) {
+            return Err(Error::OutOfRange(port, self.range));
+        }
+        if !self.is_free(port) {
+            return Err(Error::InUse(port));
+        }
+        self.used.insert(port);
+        Ok(())
+    }
+
+    // Release ports for reuse. Ports that are not used are ignored.
+    pub fn release(&mut self, ports: &[u32]) {
+        for port in ports {
+            self.used.remove(port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+
+    use super::*;
+
+    fn range(first: u32, last: u32) -> PortRange {
+        PortRange::new(first, last).unwrap()
+    }
+
+    #[test]
+    fn parse_range() {
+        assert_eq!("1024-2047".parse(), Ok(range(1024, 2047)));
+        assert_eq!("1234-1234".parse(), Ok(range(1234, 1234)));
+        for s in [
+            "",
+            "1024",
+            "1024-",
+            "-2047",
+            "2047-1024",
+            "0-10",
+            "a-b",
+            "1-4294967295",
+        ] {
+            assert_eq!(
+                s.parse::<PortRange>(),
+                Err(Error::InvalidRange(s.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn allocate_in_order() {
+        let mut allocator = PortAllocator::new(range(1234, 2000));
+        assert_eq!(allocator.allocate(3), Ok(vec![1234, 1235, 1236]));
+        assert_eq!(allocator.allocate(0), Ok(vec![]));
+        assert_eq!(allocator.allocate(1), Ok(vec![1237]));
+    }
+
+    #[test]
+    fn skip_reserved_ports() {
+        let mut allocator = PortAllocator::new(range(9995, 10000));
+        assert_eq!(allocator.allocate(3), Ok(vec![9995, 9998, 10000]));
+        assert_eq!(
+            allocator.allocate(1),
+            Err(Error::Exhausted(1, range(9995, 10000)))
+        );
+    }
+
+    #[test]
+    fn exhaust_and_release() {
+        let mut allocator = PortAllocator::new(range(100, 103));
+        let ports = allocator.allocate(3).unwrap();
+        assert_eq!(
+            allocator.allocate(2),
+            Err(Error::Exhausted(2, range(100, 103)))
+        );
+        // A failed allocation takes no ports.
+        assert_eq!(allocator.allocate(1), Ok(vec![103]));
+        assert_eq!(
+            allocator.allocate(1),
+            Err(Error::Exhausted(1, range(100, 103)))
+        );
+        allocator.release(&ports[1..2]);
+        assert_eq!(allocator.allocate(1), Ok(vec![101]));
+    }
+
+    #[test]
+    fn reuse_after_wrapping_around() {
+        let mut allocator = PortAllocator::new(range(100, 104));
+        let first = allocator.allocate(2).unwrap();
+        allocator.release(&first);
+        // The released ports are not reused while the rest of the range is free.
+        assert_eq!(allocator.allocate(3), Ok(vec![102, 103, 104]));
+        assert_eq!(allocator.allocate(2), Ok(vec![100, 101]));
+    }
+
+    #[test]
+    fn claim_ports() {
+        let mut allocator = PortAllocator::new(range(100, 103));
+        assert_eq!(allocator.claim(101), Ok(()));
+        assert_eq!(allocator.claim(101), Err(Error::InUse(101)));
+        assert_eq!(
+            allocator.claim(99),
+            Err(Error::OutOfRange(99, range(100, 103)))
+        );
+        assert_eq!(allocator.allocate(3), Ok(vec![100, 102, 103]));
+
+        let mut allocator = PortAllocator::new(range(9990, 9999));
+        assert_eq!(allocator.claim(AGENT_VSOCK_PORT), Err(Error::InUse(9999)));
+    }
+
+    #[test]
+    fn release_unused_ports() {
+        let mut allocator = PortAllocator::new(range(100, 101));
+        allocator.release(&[100, 200]);
+        assert_eq!(allocator.allocate(2), Ok(vec![100, 101]));
+    }
+
+    #[test]
+    fn allocate_under_churn() {
+        let range = range(9990, 10005);
+        let mut allocator = PortAllocator::new(range);
+        let mut live: VecDeque<Vec<u32>> = VecDeque::new();
+        let mut owners = HashMap::new();
+        // A deterministic mix of processes with 0 to 3 streams, released out of order.
+        let mut seed = 1u32;
+        for i in 0..10000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let count = (seed >> 16) as usize % 4;
+            match allocator.allocate(count) {
+                Ok(ports) => {
+                    assert_eq!(ports.len(), count);
+                    for port in &ports {
+                        assert!(range.contains(*port));
+                        assert!(!RESERVED_PORTS.contains(port));
+                        assert_eq!(owners.insert(*port, i), None, "{} is allocated twice", port);
+                    }
+                    live.push_back(ports);
+                }
+                Err(e) => {
+                    let free = range.len() as usize - RESERVED_PORTS.len() - owners.len();
+                    assert_eq!(e, Error::Exhausted(count, range));
+                    assert!(free < count);
+                }
+            }
+            if live.len() > 8 || seed & 1 == 0 {
+                let index = (seed >> 8) as usize % live.len().max(1);
+                if let Some(ports) = live.remove(index) {
+                    for port in &ports {
+                        owners.remove(port);
+                    }
+                    allocator.release(&ports);
+                }
+            }
+        }
+        assert_eq!(allocator.used.len(), owners.len());
+    }
+}
@@ -5,7 +5,9 @@ use async_trait::async_trait;
 use containerd_shim::{
     api::{
         ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest,
-        Empty, KillRequest, StartRequest, StartResponse, StateRequest, StateResponse,
+        Empty, ExecProcessRequest, KillRequest, PidsRequest, PidsResponse, ResizePtyRequest,
+        StartRequest, StartResponse, StateRequest, StateResponse, StatsRequest, StatsResponse,
+        WaitRequest, WaitResponse,
     },
     protos::shim_async::TaskClient,
     Context, DeleteResponse, Task as ShimTask, TtrpcContext, TtrpcResult,
@@ -37,10 +39,22 @@ impl ShimTask for Task {
         Ok(self.client.delete(Context::default(), &req).await?)
     }
 
+    async fn exec(&self, _ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        Ok(self.client.exec(Context::default(), &req).await?)
+    }
+
     async fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
         Ok(self.client.kill(Context::default(), &req).await?)
     }
 
+    async fn pids(&self, _ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        Ok(self.client.pids(Context::default(), &req).await?)
+    }
+
+    async fn resize_pty(&self, _ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        Ok(self.client.resize_pty(Context::default(), &req).await?)
+    }
+
     async fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
         Ok(self.client.start(Context::default(), &req).await?)
     }
@@ -48,4 +62,12 @@ impl ShimTask for Task {
     async fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
         Ok(self.client.state(Context::default(), &req).await?)
     }
+
+    async fn stats(&self, _ctx: &TtrpcContext, req: StatsRequest) -> TtrpcResult<StatsResponse> {
+        Ok(self.client.stats(Context::default(), &req).await?)
+    }
+
+    async fn wait(&self, _ctx: &TtrpcContext, req: WaitRequest) -> TtrpcResult<WaitResponse> {
+        Ok(self.client.wait(Context::default(), &req).await?)
+    }
 }
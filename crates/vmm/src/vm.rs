@@ -2,8 +2,12 @@
 // Copyright (C) 2024 Akira Moroo
 
 use std::{
+    fs::Permissions,
     ops::Deref,
-    os::{fd::FromRawFd, unix::net::UnixStream},
+    os::{
+        fd::{BorrowedFd, FromRawFd},
+        unix::{fs::PermissionsExt, net::UnixStream},
+    },
     path::Path,
     rc::Rc,
     sync::{mpsc, RwLock},
@@ -35,6 +39,8 @@ pub enum Error {
     LockPoisoned,
     #[error("Invalid vsock port")]
     InvalidVsockPort,
+    #[error("Failed to connect to vsock port {0}")]
+    FailedToConnect(u32),
     #[error(transparent)]
     Io(#[from] std::io::Error),
 }
@@ -133,6 +139,8 @@ impl Vm {
 
     pub fn connect(&mut self, port: u32, client_path: &Path) -> Result<(), Error> {
         let listener = UnixListener::bind(client_path)?;
+        // Only the user may connect to the agent.
+        std::fs::set_permissions(client_path, Permissions::from_mode(0o600))?;
         let listener = Rc::new(tokio::sync::RwLock::new(listener));
 
         let (tx, rx) = mpsc::channel::<Result<(), Error>>();
@@ -189,6 +197,47 @@ impl Vm {
         }
     }
 
+    // Open a new connection to a vsock port of the guest. Unlike `connect`, which proxies a Unix
+    // domain socket to a single connection, each call makes its own connection.
+    pub fn connect_stream(&self, port: u32) -> Result<UnixStream, Error> {
+        let (tx, rx) = mpsc::channel::<Result<UnixStream, Error>>();
+        let vm = self.vm.clone();
+        let block = RcBlock::new(move || {
+            let tx = tx.clone();
+            let err_tx = tx.clone();
+            let completion_handler = RcBlock::new(
+                move |connection: *mut VZVirtioSocketConnection, error: *mut NSError| {
+                    let Some(connection) = (unsafe { connection.as_ref() }) else {
+                        if let Some(error) = unsafe { error.as_ref() } {
+                            info!("error: {:?}", error);
+                        }
+                        let _ = err_tx.send(Err(Error::FailedToConnect(port)));
+                        return;
+                    };
+                    // The connection closes its descriptor when it is released, so keep a
+                    // duplicate of it.
+                    let fd = unsafe { BorrowedFd::borrow_raw(connection.fileDescriptor()) };
+                    let result = fd
+                        .try_clone_to_owned()
+                        .map(UnixStream::from)
+                        .map_err(Error::from);
+                    let _ = err_tx.send(result);
+                },
+            );
+
+            match vm.write() {
+                Ok(vm) => unsafe {
+                    let socket = vm.socketDevices().firstObject().unwrap();
+                    Self::do_connect(socket, port, completion_handler);
+                },
+                Err(_) => tx.send(Err(Error::LockPoisoned)).expect("Failed to send"),
+            }
+        });
+        self.queue.exec_block_async(&block);
+
+        rx.recv()?
+    }
+
     fn vsock_handler(
         stream: &mut UnixStream,
         port: u32,